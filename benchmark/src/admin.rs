@@ -0,0 +1,116 @@
+//! Admin socket of the receiver, a Unix socket answering every connection
+//! with the status of the relay reservation as one line of JSON, e.g., for
+//! `socat - UNIX-CONNECT:<path>` or health checks of a receiver kept
+//! reachable with `--reserve-only`.
+
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use libp2p::core::multiaddr::Multiaddr;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::signal;
+
+/// Status of the reservation on the relay.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReservationStatus {
+    pub relay: Multiaddr,
+    pub reserved: bool,
+    /// Circuit address senders reach the receiver at while reserved.
+    pub circuit_addr: Option<Multiaddr>,
+    pub accepted_at_ms: Option<u64>,
+    pub renewed_at_ms: Option<u64>,
+    /// Renewals since the reservation was accepted.
+    pub renewals: u64,
+    /// Why the reservation was last refused or lost.
+    pub last_error: Option<String>,
+}
+
+/// Reservation status served on the admin socket.
+#[derive(Clone)]
+pub struct AdminSocket {
+    status: Arc<Mutex<ReservationStatus>>,
+}
+
+impl AdminSocket {
+    /// Serve the status of the reservation on `relay` at `path`, replacing
+    /// a socket left behind by an earlier receiver.
+    #[cfg(unix)]
+    pub async fn bind(path: &Path, relay: &Multiaddr) -> Result<Self, Box<dyn Error>> {
+        use std::os::unix::fs::FileTypeExt;
+
+        use async_std::os::unix::net::UnixListener;
+        use futures::{AsyncWriteExt, StreamExt};
+
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path).await?;
+        info!("Serving reservation status on {}", path.display());
+
+        let socket = AdminSocket {
+            status: Arc::new(Mutex::new(ReservationStatus {
+                relay: relay.clone(),
+                reserved: false,
+                circuit_addr: None,
+                accepted_at_ms: None,
+                renewed_at_ms: None,
+                renewals: 0,
+                last_error: None,
+            })),
+        };
+        let status = socket.status.clone();
+        async_std::task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                let line = {
+                    let status = status.lock().expect("not poisoned");
+                    serde_json::to_string(&*status).expect("status serializes") + "\n"
+                };
+                let result = match stream {
+                    Ok(mut stream) => stream.write_all(line.as_bytes()).await,
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = result {
+                    warn!("Admin socket: {}", error);
+                }
+            }
+        });
+        Ok(socket)
+    }
+
+    #[cfg(not(unix))]
+    pub async fn bind(_path: &Path, _relay: &Multiaddr) -> Result<Self, Box<dyn Error>> {
+        Err("the admin socket is only supported on Unix".into())
+    }
+
+    /// Record that the relay accepted or renewed the reservation for
+    /// `circuit_addr`.
+    pub fn accepted(&self, circuit_addr: Multiaddr, renewal: bool) {
+        let now = Some(signal::unix_time_ms(SystemTime::now()));
+        let mut status = self.status.lock().expect("not poisoned");
+        if renewal && status.reserved {
+            status.renewals += 1;
+            status.renewed_at_ms = now;
+        } else {
+            status.accepted_at_ms = now;
+            status.renewed_at_ms = None;
+            status.renewals = 0;
+        }
+        status.reserved = true;
+        status.circuit_addr = Some(circuit_addr);
+        status.last_error = None;
+    }
+
+    /// Record that the reservation was refused or lost because of `reason`,
+    /// or cancelled if `None`.
+    pub fn lost(&self, reason: Option<String>) {
+        let mut status = self.status.lock().expect("not poisoned");
+        status.reserved = false;
+        status.circuit_addr = None;
+        status.last_error = reason;
+    }
+}
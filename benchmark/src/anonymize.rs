@@ -0,0 +1,45 @@
+//! Keyed hashing of peer ids and addresses, so result records from
+//! collaborators' networks can be pooled without revealing their topology.
+//! The same key maps the same peer or address to the same pseudonym, so
+//! records can still be grouped by path.
+
+use hmac::{Hmac, Mac};
+use libp2p::{Multiaddr, PeerId};
+use sha2::Sha256;
+
+/// Number of bytes of the HMAC kept in pseudonyms.
+const PSEUDONYM_LEN: usize = 8;
+
+pub struct Anonymizer {
+    mac: Hmac<Sha256>,
+}
+
+impl Anonymizer {
+    pub fn new(key: &str) -> Self {
+        Anonymizer {
+            mac: Hmac::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    /// Pseudonym of `peer_id`, e.g., `peer-3f2a9c0d1e8b7a65`.
+    pub fn peer_id(&self, peer_id: &PeerId) -> String {
+        self.pseudonym("peer", &peer_id.to_bytes())
+    }
+
+    /// Pseudonym of `addr`, e.g., `addr-0b1c2d3e4f5a6b7c`.
+    pub fn multiaddr(&self, addr: &Multiaddr) -> String {
+        self.pseudonym("addr", &addr.to_vec())
+    }
+
+    fn pseudonym(&self, kind: &str, bytes: &[u8]) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(kind.as_bytes());
+        mac.update(bytes);
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest[..PSEUDONYM_LEN]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        format!("{kind}-{hex}")
+    }
+}
@@ -0,0 +1,125 @@
+//! Append-only audit log of connection events, kept separate from the debug
+//! log so connections to a publicly reachable peer can be reviewed later.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use libp2p::bandwidth::BandwidthSinks;
+use libp2p::swarm::SwarmEvent;
+
+/// Audit log rotated by size. When the log would exceed `max_size` bytes it
+/// is renamed to `<path>.1`, older logs are shifted to `<path>.2` and so on,
+/// and at most `max_files` rotated logs are kept.
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Record connection events. Byte totals are cumulative over all
+    /// connections of the process since per-connection counters are not
+    /// available.
+    pub fn record_swarm_event<B, E: std::fmt::Debug>(
+        &mut self,
+        event: &SwarmEvent<B, E>,
+        bandwidth: &BandwidthSinks,
+    ) -> io::Result<()> {
+        let record = match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => format!(
+                "open peer={} connection={:?} endpoint={:?}",
+                peer_id, connection_id, endpoint
+            ),
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                cause,
+                ..
+            } => format!(
+                "close peer={} connection={:?} endpoint={:?} cause={:?}",
+                peer_id, connection_id, endpoint, cause
+            ),
+            SwarmEvent::IncomingConnectionError {
+                connection_id,
+                send_back_addr,
+                error,
+                ..
+            } => format!(
+                "reject connection={:?} from={} error={}",
+                connection_id, send_back_addr, error
+            ),
+            _ => return Ok(()),
+        };
+        self.record(&format!(
+            "{} total_in={} total_out={}",
+            record,
+            bandwidth.total_inbound(),
+            bandwidth.total_outbound()
+        ))
+    }
+
+    /// Record that a resource threshold was exceeded.
+    pub fn record_alarm(&mut self, reason: &str) -> io::Result<()> {
+        self.record(&format!("alarm {}", reason))
+    }
+
+    fn record(&mut self, record: &str) -> io::Result<()> {
+        let line = format!(
+            "{} {}\n",
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            record
+        );
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.as_os_str().to_owned();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        for n in (1..self.max_files).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
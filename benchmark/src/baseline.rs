@@ -0,0 +1,81 @@
+//! Local cache of the last result of each benchmark configuration, used to
+//! show how a run compares to the previous one.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+/// Last result of a benchmark configuration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Baseline {
+    pub recorded_at: String,
+    pub metrics: BTreeMap<String, f64>,
+}
+
+/// Baselines keyed by benchmark configuration, stored as JSON.
+pub struct BaselineCache {
+    path: PathBuf,
+    baselines: BTreeMap<String, Baseline>,
+}
+
+impl BaselineCache {
+    /// Load the cache at `path`, starting empty if it does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let baselines = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| format!("invalid baseline cache {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(BaselineCache {
+            path: path.to_path_buf(),
+            baselines,
+        })
+    }
+
+    /// Replace the baseline of `key` with `metrics` and save the cache,
+    /// returning the previous baseline.
+    pub fn update(
+        &mut self,
+        key: &str,
+        metrics: &[(&str, f64)],
+    ) -> Result<Option<Baseline>, Box<dyn Error>> {
+        let baseline = Baseline {
+            recorded_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            metrics: metrics
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        };
+        let previous = self.baselines.insert(key.to_string(), baseline);
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&self.baselines)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        Ok(previous)
+    }
+}
+
+/// Format the relative change of each metric versus `previous`, e.g.,
+/// `upload Mbit/s 95.10 -> 101.30 (+6.5%)`.
+pub fn format_deltas(previous: &Baseline, metrics: &[(&str, f64)]) -> String {
+    metrics
+        .iter()
+        .filter_map(|(name, value)| {
+            let before = previous.metrics.get(*name)?;
+            Some(format!(
+                "{} {:.2} -> {:.2} ({:+.1}%)",
+                name,
+                before,
+                value,
+                100.0 * (value - before) / before
+            ))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
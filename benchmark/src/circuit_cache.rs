@@ -0,0 +1,80 @@
+//! Local cache of the receiver's circuit address last reached via each relay,
+//! so quick successive sender invocations can skip discovering the receiver.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+
+use crate::signal::unix_time_ms;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    cached_at_unix_ms: u64,
+    circuit_addr: String,
+}
+
+/// A cached circuit address that is still fresh.
+#[derive(Clone, Debug)]
+pub struct CachedCircuit {
+    pub circuit_addr: Multiaddr,
+    pub age: Duration,
+}
+
+/// Circuit addresses keyed by relay multiaddr, stored as JSON.
+pub struct CircuitCache {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl CircuitCache {
+    /// Load the cache at `path`, starting empty if it does not exist yet.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| format!("invalid circuit cache {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(CircuitCache {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// The circuit address cached for `relay` unless it is older than `ttl`.
+    pub fn get(&self, relay: &Multiaddr, ttl: Duration) -> Option<CachedCircuit> {
+        let entry = self.entries.get(&relay.to_string())?;
+        let now = unix_time_ms(SystemTime::now());
+        let age = Duration::from_millis(now.saturating_sub(entry.cached_at_unix_ms));
+        if age > ttl {
+            return None;
+        }
+        Some(CachedCircuit {
+            circuit_addr: entry.circuit_addr.parse().ok()?,
+            age,
+        })
+    }
+
+    /// Replace the circuit address cached for `relay` and save the cache.
+    pub fn update(
+        &mut self,
+        relay: &Multiaddr,
+        circuit_addr: &Multiaddr,
+    ) -> Result<(), Box<dyn Error>> {
+        let entry = Entry {
+            cached_at_unix_ms: unix_time_ms(SystemTime::now()),
+            circuit_addr: circuit_addr.to_string(),
+        };
+        self.entries.insert(relay.to_string(), entry);
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&self.entries)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Exporters of completed throughput runs, so results accumulate outside the
+//! logs. Several exporters can be active at once, each chosen with
+//! `--export <name>[:<target>]`, and binaries embedding the sender can add
+//! their own to an [`ExporterRegistry`].
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+
+use crate::TransportMethod;
+
+const CSV_HEADER: &str = "timestamp,transport,upload_bytes,upload_seconds,upload_mbps,\
+                          download_bytes,download_seconds,download_mbps,relay,path";
+
+/// A completed throughput run.
+pub struct RunResult {
+    pub finished_at: SystemTime,
+    pub transport: TransportMethod,
+    pub upload_bytes: usize,
+    pub upload: Duration,
+    pub download_bytes: usize,
+    pub download: Duration,
+    /// The relay's multiaddr, or its pseudonym with --anonymize-key.
+    pub relay: String,
+    /// Whether a relayed connection to the receiver was open, so the run may
+    /// have been sent over the relay instead of the direct connection.
+    pub relayed: bool,
+}
+
+impl RunResult {
+    fn transport_name(&self) -> String {
+        let transport = self
+            .transport
+            .to_possible_value()
+            .expect("no transport is skipped");
+        transport.get_name().to_string()
+    }
+
+    fn path(&self) -> &'static str {
+        if self.relayed {
+            "relayed"
+        } else {
+            "direct"
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "timestamp": humantime::format_rfc3339_millis(self.finished_at).to_string(),
+            "transport": self.transport_name(),
+            "upload_bytes": self.upload_bytes,
+            "upload_seconds": self.upload.as_secs_f64(),
+            "upload_mbps": mbps(self.upload_bytes, self.upload),
+            "download_bytes": self.download_bytes,
+            "download_seconds": self.download.as_secs_f64(),
+            "download_mbps": mbps(self.download_bytes, self.download),
+            "relay": self.relay,
+            "path": self.path(),
+        })
+    }
+}
+
+fn mbps(bytes: usize, duration: Duration) -> f64 {
+    bytes as f64 * 8.0 / 1e6 / duration.as_secs_f64()
+}
+
+/// A destination for the results of throughput runs.
+pub trait ResultsExporter {
+    /// Export a completed run.
+    fn export(&mut self, result: &RunResult) -> Result<(), Box<dyn Error>>;
+}
+
+/// Creates an exporter from the target of `--export <name>:<target>`, if
+/// one was given.
+pub type ExporterFactory = fn(Option<&str>) -> Result<Box<dyn ResultsExporter>, Box<dyn Error>>;
+
+/// Exporters by name.
+pub struct ExporterRegistry {
+    factories: BTreeMap<&'static str, ExporterFactory>,
+}
+
+impl Default for ExporterRegistry {
+    /// The built-in exporters: `csv:<path>`, `jsonl:<path>`, `stdout`, and
+    /// `interop`.
+    fn default() -> Self {
+        let mut registry = ExporterRegistry {
+            factories: BTreeMap::new(),
+        };
+        registry.register("csv", |target| {
+            Ok(Box::new(CsvExporter {
+                path: required_path("csv", target)?,
+            }))
+        });
+        registry.register("jsonl", |target| {
+            Ok(Box::new(JsonLinesExporter {
+                path: required_path("jsonl", target)?,
+            }))
+        });
+        registry.register("stdout", |_| Ok(Box::new(StdoutExporter)));
+        registry.register("interop", |_| Ok(Box::new(InteropExporter)));
+        registry
+    }
+}
+
+impl ExporterRegistry {
+    /// Add an exporter, replacing any exporter of the same name.
+    pub fn register(&mut self, name: &'static str, factory: ExporterFactory) {
+        self.factories.insert(name, factory);
+    }
+
+    /// Create the exporter for `spec`, e.g., `csv:results.csv`.
+    pub fn create(&self, spec: &str) -> Result<Box<dyn ResultsExporter>, Box<dyn Error>> {
+        let (name, target) = match spec.split_once(':') {
+            Some((name, target)) => (name, Some(target)),
+            None => (spec, None),
+        };
+        let factory = self.factories.get(name).ok_or_else(|| {
+            let names: Vec<_> = self.factories.keys().copied().collect();
+            format!(
+                "unknown exporter '{name}', expected one of {}",
+                names.join(", ")
+            )
+        })?;
+        factory(target)
+    }
+}
+
+fn required_path(name: &str, target: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+    match target {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(format!("exporter '{name}' requires a path, e.g., {name}:results.{name}").into()),
+    }
+}
+
+fn append_to(path: &PathBuf) -> Result<File, Box<dyn Error>> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("failed to open {}: {e}", path.display()).into())
+}
+
+/// Appends one row per run to a CSV file, writing the header first if the
+/// file is new or empty.
+struct CsvExporter {
+    path: PathBuf,
+}
+
+impl ResultsExporter for CsvExporter {
+    fn export(&mut self, result: &RunResult) -> Result<(), Box<dyn Error>> {
+        let mut file = append_to(&self.path)?;
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{CSV_HEADER}")?;
+        }
+        // Multiaddrs and pseudonyms never contain commas or quotes, so no
+        // field needs quoting.
+        writeln!(
+            file,
+            "{},{},{},{:.6},{:.2},{},{:.6},{:.2},{},{}",
+            humantime::format_rfc3339_millis(result.finished_at),
+            result.transport_name(),
+            result.upload_bytes,
+            result.upload.as_secs_f64(),
+            mbps(result.upload_bytes, result.upload),
+            result.download_bytes,
+            result.download.as_secs_f64(),
+            mbps(result.download_bytes, result.download),
+            result.relay,
+            result.path(),
+        )?;
+        Ok(())
+    }
+}
+
+/// Appends one JSON object per run to a file.
+struct JsonLinesExporter {
+    path: PathBuf,
+}
+
+impl ResultsExporter for JsonLinesExporter {
+    fn export(&mut self, result: &RunResult) -> Result<(), Box<dyn Error>> {
+        let mut file = append_to(&self.path)?;
+        writeln!(file, "{}", result.to_json())?;
+        Ok(())
+    }
+}
+
+/// Prints one JSON object per run to stdout.
+struct StdoutExporter;
+
+impl ResultsExporter for StdoutExporter {
+    fn export(&mut self, result: &RunResult) -> Result<(), Box<dyn Error>> {
+        println!("{}", result.to_json());
+        Ok(())
+    }
+}
+
+/// Prints one JSON result per run to stdout in the format of the libp2p perf
+/// interop dashboards.
+struct InteropExporter;
+
+impl ResultsExporter for InteropExporter {
+    fn export(&mut self, result: &RunResult) -> Result<(), Box<dyn Error>> {
+        let interop = serde_json::json!({
+            "type": "final",
+            "timeSeconds": (result.upload + result.download).as_secs_f64(),
+            "uploadBytes": result.upload_bytes,
+            "downloadBytes": result.download_bytes,
+        });
+        println!("{interop}");
+        Ok(())
+    }
+}
@@ -1,20 +1,744 @@
 use std::error::Error;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::Duration;
 
 use clap::ValueEnum;
+use futures::future::Either;
 use futures::{FutureExt, StreamExt};
+use ipnet::IpNet;
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{timeout::TransportTimeout, Boxed, Transport};
+use libp2p::core::upgrade;
 use libp2p::identity::Keypair;
+use libp2p::swarm::dial_opts::DialOpts;
 use libp2p::swarm::NetworkBehaviour;
+use libp2p::swarm::StreamUpgradeError;
 use libp2p::swarm::Swarm;
 use libp2p::swarm::SwarmEvent;
-use log::info;
+use libp2p::{dns, identify, noise, plaintext, quic, relay, tcp, yamux, PeerId, StreamProtocol};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, ValueEnum)]
+use crate::quickack::QuickAck;
+
+pub mod admin;
+pub mod anonymize;
+pub mod audit;
+pub mod baseline;
+pub mod circuit_cache;
+pub mod export;
+pub mod muxing;
+pub mod quickack;
+pub mod receive;
+pub mod results;
+pub mod schedule;
+pub mod selftest;
+pub mod send;
+pub mod signal;
+pub mod stalls;
+pub mod stats;
+pub mod stress;
+pub mod throttle;
+pub mod trace;
+pub mod transfers;
+pub mod warning;
+
+/// Options shared by the sender and receiver.
+#[derive(clap::Args, Clone, Debug)]
+pub struct CommonArgs {
+    // Seed used to generate deterministic peer id.
+    #[arg(short, long, required_unless_present = "ephemeral_identity")]
+    pub seed: Option<u8>,
+
+    // Generate a fresh random identity instead of deriving it from --seed,
+    // so repeated invocations do not reuse a peer id.
+    #[arg(long, conflicts_with = "seed")]
+    pub ephemeral_identity: bool,
+
+    // Relay server multi-address.
+    #[arg(short, long)]
+    pub relay_multiaddr: Multiaddr,
+
+    // Transport method (tcp or quic-v1).
+    // Should match the transport method of relay_multiaddr.
+    #[arg(short, long, value_enum, default_value_t=TransportMethod::Tcp)]
+    pub transport: TransportMethod,
+
+    // Prefix of the benchmark's own protocol names, e.g., /mylab/bench for
+    // /mylab/bench/signal/1. Sender and receiver must use the same prefix.
+    #[arg(
+        long,
+        value_parser = signal::parse_protocol_prefix,
+        default_value = signal::DEFAULT_PROTOCOL_PREFIX
+    )]
+    pub protocol_prefix: String,
+}
+
+impl CommonArgs {
+    /// Name of the protocol sender and receiver coordinate runs over.
+    pub fn signal_protocol(&self) -> StreamProtocol {
+        signal::protocol_name(&self.protocol_prefix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum TransportMethod {
     Tcp,
     TcpNoDelay,
     QuicV1,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionMode {
+    // Keep the direct connection open across runs.
+    Persistent,
+    // Close the connection after each run and hole punch again for the next.
+    OnDemand,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BenchMode {
+    // Transfer the payload over a single stream per run.
+    Throughput,
+    // Open and close many short-lived streams per run.
+    StreamChurn,
+    // Dial many fresh connections per run, measuring the handshake latency.
+    ConnectionRate,
+    // Leave the direct connection idle for a longer gap each run, then probe
+    // it, to find the NAT binding timeout.
+    NatBinding,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum EventHandling {
+    // Panic on unexpected swarm events, useful for debugging.
+    Strict,
+    // Log unexpected swarm events and continue.
+    Lenient,
+}
+
+impl EventHandling {
+    /// Handle a swarm event the caller did not expect at this point.
+    pub fn unexpected<E: std::fmt::Debug>(self, event: E) {
+        match self {
+            EventHandling::Strict => panic!("{event:?}"),
+            EventHandling::Lenient => warn!("Ignoring unexpected event: {event:?}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum DiscoveryMethod {
+    // Read the receiver's circuit address from --ticket-file.
+    Ticket,
+    // Use --receiver-peer-id.
+    Cli,
+    // Use the receiver last reached via --relay-multiaddr, if cached within
+    // --circuit-cache-ttl.
+    Cache,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Preset {
+    // A single small run to check that hole punching works.
+    Smoke,
+    // Several large throughput runs in both directions.
+    Throughput,
+    // Handshake latency of many fresh direct connections.
+    Latency,
+    // Many small runs, hole punching again for each.
+    HolepunchCampaign,
+}
+
+impl Preset {
+    /// Flags the preset expands to.
+    pub fn args(self) -> &'static [&'static str] {
+        match self {
+            Preset::Smoke => &["--payload", "1MB", "--runs", "1", "--dial-timeout", "30s"],
+            Preset::Throughput => &[
+                "--bench",
+                "throughput",
+                "--payload",
+                "100MB",
+                "--runs",
+                "5",
+                "--both-directions",
+                "--tuning-advice",
+            ],
+            Preset::Latency => &[
+                "--bench",
+                "connection-rate",
+                "--connections",
+                "100",
+                "--runs",
+                "3",
+            ],
+            Preset::HolepunchCampaign => &[
+                "--payload",
+                "1MB",
+                "--runs",
+                "20",
+                "--connection-mode",
+                "on-demand",
+                "--event-handling",
+                "lenient",
+            ],
+        }
+    }
+}
+
+/// Insert the flags of the `--preset` in `args` right after the program
+/// name, so flags given explicitly override them when parsed with
+/// `args_override_self`. An invalid preset is left for clap to report.
+pub fn expand_preset(args: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut args: Vec<String> = args.into_iter().collect();
+    let name = args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == "--preset" {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix("--preset=").map(str::to_string)
+        }
+    });
+    if let Some(preset) = name.and_then(|name| Preset::from_str(&name, false).ok()) {
+        let position = args.len().min(1);
+        args.splice(
+            position..position,
+            preset.args().iter().map(|arg| arg.to_string()),
+        );
+    }
+    args
+}
+
+/// Features compiled into a binary, printed by `--version --json` so
+/// orchestration can check a binary supports a scenario before dispatching
+/// it.
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub runtime: &'static str,
+    pub transports: Vec<TransportMethod>,
+    pub security: Vec<&'static str>,
+    pub muxers: Vec<&'static str>,
+    pub benchmarks: Vec<BenchMode>,
+    pub protocols: Vec<String>,
+    pub behaviours: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// Build report of the binary `name` running `behaviours`.
+    pub fn new(name: &'static str, behaviours: &[&'static str]) -> Self {
+        BuildInfo {
+            name,
+            version: env!("CARGO_PKG_VERSION"),
+            runtime: "async-std",
+            transports: TransportMethod::value_variants().to_vec(),
+            security: vec!["noise", "plaintext"],
+            muxers: vec!["yamux"],
+            benchmarks: BenchMode::value_variants().to_vec(),
+            protocols: vec![
+                libp2p_perf::PROTOCOL_NAME.to_string(),
+                signal::PROTOCOL_NAME.to_string(),
+            ],
+            behaviours: behaviours.to_vec(),
+        }
+    }
+
+    /// Print the report and exit if `args` contain both `--version` (or
+    /// `-V`) and `--json`. Clap handles `--version` alone.
+    pub fn print_if_requested(&self, args: &[String]) -> Result<(), Box<dyn Error>> {
+        let version = args.iter().any(|arg| arg == "--version" || arg == "-V");
+        if version && args.iter().any(|arg| arg == "--json") {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            std::process::exit(0);
+        }
+        Ok(())
+    }
+}
+
+/// Benchmark capabilities a receiver advertises in its identify agent
+/// version, e.g.
+/// `benchmark/0.1.0 (modes=throughput,stream-churn; max-payload=1073741824)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    pub version: String,
+    pub modes: Vec<BenchMode>,
+    pub max_payload: usize,
+}
+
+impl Capabilities {
+    const AGENT_PREFIX: &'static str = "benchmark/";
+
+    /// Capabilities of this build, supporting every benchmark mode.
+    pub fn local(max_payload: usize) -> Self {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            modes: BenchMode::value_variants().to_vec(),
+            max_payload,
+        }
+    }
+
+    pub fn to_agent_version(&self) -> String {
+        let modes: Vec<String> = self
+            .modes
+            .iter()
+            .filter_map(|mode| mode.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        format!(
+            "{}{} (modes={}; max-payload={})",
+            Self::AGENT_PREFIX,
+            self.version,
+            modes.join(","),
+            self.max_payload
+        )
+    }
+
+    /// Parse capabilities from an agent version, returning `None` if the
+    /// peer is not a benchmark receiver. Unknown modes and fields are
+    /// ignored so newer receivers remain readable.
+    pub fn from_agent_version(agent_version: &str) -> Option<Self> {
+        let (version, fields) = agent_version
+            .strip_prefix(Self::AGENT_PREFIX)?
+            .split_once(" (")?;
+        let mut modes = None;
+        let mut max_payload = None;
+        for field in fields.strip_suffix(')')?.split("; ") {
+            match field.split_once('=')? {
+                ("modes", value) => {
+                    modes = Some(
+                        value
+                            .split(',')
+                            .filter_map(|mode| BenchMode::from_str(mode, false).ok())
+                            .collect(),
+                    )
+                }
+                ("max-payload", value) => max_payload = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(Capabilities {
+            version: version.to_string(),
+            modes: modes?,
+            max_payload: max_payload?,
+        })
+    }
+
+    /// Check that a sender of this build can run `mode` with `payload` bytes
+    /// against a receiver advertising these capabilities.
+    pub fn check_compatible(&self, mode: BenchMode, payload: usize) -> Result<(), String> {
+        let local = env!("CARGO_PKG_VERSION");
+        if release(&self.version) != release(local) {
+            return Err(format!(
+                "receiver runs benchmark {} which is incompatible with this sender's {}; \
+                 run the same release on both ends",
+                self.version, local
+            ));
+        }
+        if !self.modes.contains(&mode) {
+            return Err(format!(
+                "receiver does not support the {:?} benchmark (supports {:?})",
+                mode, self.modes
+            ));
+        }
+        if payload > self.max_payload {
+            return Err(format!(
+                "payload of {} bytes exceeds the receiver's maximum of {} bytes; \
+                 lower --payload or restart the receiver with a larger --max-payload",
+                payload, self.max_payload
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The semver-compatible part of a version: the major version, or the major
+/// and minor version before 1.0.
+fn release(version: &str) -> Vec<&str> {
+    let parts: Vec<&str> = version.split('.').collect();
+    match parts.as_slice() {
+        ["0", minor, ..] => vec!["0", minor],
+        [major, ..] => vec![major],
+        [] => vec![],
+    }
+}
+
+/// Per-stream receive window of the multiplexer used by `transport`: the
+/// yamux default for TCP and libp2p-quic's default `max_stream_data` for
+/// QUIC.
+pub fn stream_receive_window(transport: TransportMethod) -> usize {
+    match transport {
+        TransportMethod::Tcp | TransportMethod::TcpNoDelay => 256 * 1024,
+        TransportMethod::QuicV1 => 10_000_000,
+    }
+}
+
+/// Bandwidth-delay product of a run compared against the stream receive
+/// window, which caps a single stream's throughput at window / RTT.
+pub struct BdpReport {
+    pub transport: TransportMethod,
+    pub rtt: Duration,
+    /// Throughput in bytes per second.
+    pub throughput: f64,
+}
+
+impl BdpReport {
+    pub fn bdp(&self) -> f64 {
+        self.throughput * self.rtt.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for BdpReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let window = stream_receive_window(self.transport);
+        let (name, multiplexer) = match self.transport {
+            TransportMethod::QuicV1 => ("QUIC max_stream_data", "QUIC"),
+            _ => ("yamux receive window", "yamux"),
+        };
+        let bdp = self.bdp();
+        write!(
+            f,
+            "RTT {:.2} ms, BDP {:.0} KiB, {} {} KiB",
+            self.rtt.as_secs_f64() * 1000.0,
+            bdp / 1024.0,
+            name,
+            window / 1024,
+        )?;
+        // A stream can not have more than a window of data in flight, so
+        // a BDP close to the window means the window limited throughput.
+        if bdp >= 0.8 * window as f64 {
+            let suggested_mib = (2.0 * bdp / (1 << 20) as f64).ceil().max(1.0);
+            write!(
+                f,
+                "; throughput is likely limited by the window, increase the {} window to >= {} MiB",
+                multiplexer, suggested_mib
+            )
+        } else {
+            write!(f, "; the window is not the bottleneck")
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ThroughputUnit {
+    // Megabits per second (10^6 bits).
+    Mbps,
+    // Megabytes per second (10^6 bytes).
+    #[value(name = "MBps")]
+    MBps,
+    // Gigabits per second (10^9 bits).
+    Gbps,
+    // Bits per second with the largest SI prefix below the value.
+    Auto,
+}
+
+/// Format the throughput of transferring `bytes` in `duration`.
+///
+/// Throughput always uses SI (powers of 1000) prefixes, while sizes are
+/// formatted by [`format_bytes`] with IEC (powers of 1024) prefixes.
+pub fn format_throughput(bytes: usize, duration: Duration, unit: ThroughputUnit) -> String {
+    format_rate(bytes as f64 / duration.as_secs_f64(), unit)
+}
+
+/// Format a throughput given in bytes per second, see [`format_throughput`].
+pub fn format_rate(bytes_per_sec: f64, unit: ThroughputUnit) -> String {
+    let bits_per_sec = bytes_per_sec * 8.0;
+    match unit {
+        ThroughputUnit::Mbps => format!("{:.2} Mbit/s", bits_per_sec / 1e6),
+        ThroughputUnit::MBps => format!("{:.2} MB/s", bytes_per_sec / 1e6),
+        ThroughputUnit::Gbps => format!("{:.3} Gbit/s", bits_per_sec / 1e9),
+        ThroughputUnit::Auto if bits_per_sec >= 1e9 => {
+            format!("{:.2} Gbit/s", bits_per_sec / 1e9)
+        }
+        ThroughputUnit::Auto if bits_per_sec >= 1e6 => {
+            format!("{:.2} Mbit/s", bits_per_sec / 1e6)
+        }
+        ThroughputUnit::Auto if bits_per_sec >= 1e3 => {
+            format!("{:.2} kbit/s", bits_per_sec / 1e3)
+        }
+        ThroughputUnit::Auto => format!("{:.2} bit/s", bits_per_sec),
+    }
+}
+
+/// Format a size with IEC prefixes, e.g., `976.56 KiB`.
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Parse a human-readable size such as `250MB`, `1GiB` or `4096` into bytes.
+///
+/// SI suffixes (`KB`, `MB`, ...) are powers of 1000 and IEC suffixes (`KiB`,
+/// `MiB`, ...) are powers of 1024. A value without a suffix is in bytes.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() || fraction.contains('.') {
+        return Err(format!(
+            "invalid size '{s}': expected a number followed by a unit"
+        ));
+    }
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KB" | "kB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        unit => return Err(format!("invalid size unit '{unit}' in '{s}'")),
+    };
+    let too_large = || format!("invalid size '{s}': too large");
+    let not_whole = || format!("invalid size '{s}': not a whole number of bytes");
+
+    let whole: u64 = match whole {
+        "" => 0,
+        whole => whole.parse().map_err(|_| too_large())?,
+    };
+    // The fraction is scaled exactly on integers, as floating point error
+    // would reject whole sizes such as 1.001KB. Fractions too long to fit
+    // are finer than the multiplier's factors of 10, so never whole.
+    let fraction = fraction.trim_end_matches('0');
+    let fraction_bytes = if fraction.is_empty() {
+        0
+    } else {
+        let digits: u128 = fraction.parse().map_err(|_| not_whole())?;
+        let scale = 10u128
+            .checked_pow(fraction.len() as u32)
+            .ok_or_else(not_whole)?;
+        let scaled = digits
+            .checked_mul(multiplier.into())
+            .ok_or_else(not_whole)?;
+        if scaled % scale != 0 {
+            return Err(not_whole());
+        }
+        (scaled / scale) as u64
+    };
+    whole
+        .checked_mul(multiplier)
+        .and_then(|bytes| bytes.checked_add(fraction_bytes))
+        .and_then(|bytes| usize::try_from(bytes).ok())
+        .ok_or_else(too_large)
+}
+
+/// Parse a human-readable rate such as `1MBps`, `10MiB/s` or `4096` into
+/// bytes per second, with the units of [`parse_size`].
+pub fn parse_rate(s: &str) -> Result<usize, String> {
+    let size = s.trim();
+    let size = size
+        .strip_suffix("ps")
+        .or_else(|| size.strip_suffix("/s"))
+        .unwrap_or(size);
+    parse_size(size).map_err(|e| format!("invalid rate '{s}': {e}"))
+}
+
+/// Parse a human-readable duration such as `30s`, `5m` or `1h 30m`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    humantime::parse_duration(s).map_err(|e| format!("invalid duration '{s}': {e}"))
+}
+
+/// Parse a `key=value` label used to annotate run records.
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!("invalid label '{s}': expected key=value")),
+    }
+}
+
+/// Format labels as space separated `key=value` pairs.
+pub fn format_labels(labels: &[(String, String)]) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Set of CPU ids parsed from a list such as `0,2-3`.
+#[derive(Clone, Debug)]
+pub struct CpuList(pub Vec<usize>);
+
+/// Parse a CPU list such as `0,2-3` as used by `taskset --cpu-list`.
+pub fn parse_cpu_list(s: &str) -> Result<CpuList, String> {
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        let parse = |cpu: &str| {
+            cpu.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid CPU id '{cpu}' in '{s}'"))
+        };
+        match part.split_once('-') {
+            Some((start, end)) => cpus.extend(parse(start)?..=parse(end)?),
+            None => cpus.push(parse(part)?),
+        }
+    }
+    Ok(CpuList(cpus))
+}
+
+/// Configure the async-std runtime before it is started.
+///
+/// Must be called before the first `async_std::task::block_on` or spawn
+/// because the runtime reads its thread count once when it starts. CPU
+/// affinity is set on the calling thread and inherited by every thread the
+/// runtime spawns.
+pub fn configure_runtime(
+    worker_threads: Option<usize>,
+    cpu_affinity: Option<&CpuList>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(threads) = worker_threads {
+        if threads == 0 {
+            return Err("worker threads must be at least 1".into());
+        }
+        std::env::set_var("ASYNC_STD_THREAD_COUNT", threads.to_string());
+        info!("Worker threads: {}", threads);
+    }
+
+    if let Some(CpuList(cpus)) = cpu_affinity {
+        set_cpu_affinity(cpus)?;
+        info!("CPU affinity: {:?}", cpus);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(cpus: &[usize]) -> Result<(), Box<dyn Error>> {
+    // SAFETY: cpu_set_t is a plain bit mask for which all zeros is valid, and
+    // CPU_SET is only called with ids checked against CPU_SETSIZE.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(format!("CPU id {cpu} is out of range").into());
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpu_affinity(_cpus: &[usize]) -> Result<(), Box<dyn Error>> {
+    Err("CPU affinity is only supported on Linux".into())
+}
+
+/// Resident set size of this process in bytes.
+#[cfg(target_os = "linux")]
+pub fn resident_set_size() -> Result<u64, Box<dyn Error>> {
+    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    let pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .ok_or("malformed /proc/self/statm")?
+        .parse()?;
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_set_size() -> Result<u64, Box<dyn Error>> {
+    Err("measuring the resident set size is only supported on Linux".into())
+}
+
+/// Number of file descriptors this process has open.
+#[cfg(target_os = "linux")]
+pub fn open_fds() -> Result<usize, Box<dyn Error>> {
+    Ok(std::fs::read_dir("/proc/self/fd")?.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_fds() -> Result<usize, Box<dyn Error>> {
+    Err("counting open file descriptors is only supported on Linux".into())
+}
+
+/// Return true if a relay refused a reservation or circuit because one of its
+/// limits was exceeded.
+///
+/// The relay protocol carries no retry interval with the refusal, and
+/// libp2p-relay does not export its failure reason types, so the reason is
+/// matched on its message.
+pub fn is_rate_limited<E: Error>(error: &StreamUpgradeError<E>) -> bool {
+    match error {
+        StreamUpgradeError::Apply(reason) => reason
+            .to_string()
+            .to_lowercase()
+            .contains("resource limit exceeded"),
+        _ => false,
+    }
+}
+
+/// Exponential backoff between retries of requests a relay rate limited.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+    max_retries: usize,
+    retries: usize,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, max_retries: usize) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+            max_retries,
+            retries: 0,
+        }
+    }
+
+    /// Return the delay before the next retry, or `None` if the retries are
+    /// exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.retries >= self.max_retries {
+            return None;
+        }
+        self.retries += 1;
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        Some(delay)
+    }
+
+    /// Reset after a request succeeded.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+        self.retries = 0;
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+}
+
 pub fn generate_ed25519(seed: u8) -> Keypair {
     let mut bytes = [0u8; 32];
     bytes[0] = seed;
@@ -22,9 +746,463 @@ pub fn generate_ed25519(seed: u8) -> Keypair {
     Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
 }
 
+/// Return the peer id in the trailing `/p2p/<peer id>` component of `addr`.
+pub fn peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
+    addr.iter().last().and_then(|p| match p {
+        Protocol::P2p(peer_id) => Some(peer_id),
+        _ => None,
+    })
+}
+
+/// Describe how two observations of our public address differ, or return
+/// `None` if they agree on the IP address and port.
+pub fn compare_observed_addrs(a: &Multiaddr, b: &Multiaddr) -> Option<&'static str> {
+    fn ip_and_port(addr: &Multiaddr) -> (Option<Protocol<'_>>, Option<Protocol<'_>>) {
+        let mut protocols = addr.iter();
+        (protocols.next(), protocols.next())
+    }
+    let (a_ip, a_port) = ip_and_port(a);
+    let (b_ip, b_port) = ip_and_port(b);
+    if a_ip != b_ip {
+        Some("different IP addresses")
+    } else if a_port != b_port {
+        Some("different ports, the NAT mapping depends on the destination")
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum AddrPreference {
+    // Prefer QUIC addresses.
+    Quic,
+    // Prefer TCP addresses.
+    Tcp,
+    // Prefer IPv4 addresses.
+    Ip4,
+    // Prefer IPv6 addresses.
+    Ip6,
+}
+
+impl AddrPreference {
+    pub fn matches(self, addr: &Multiaddr) -> bool {
+        addr.iter().any(|protocol| {
+            matches!(
+                (self, protocol),
+                (AddrPreference::Quic, Protocol::QuicV1)
+                    | (AddrPreference::Tcp, Protocol::Tcp(_))
+                    | (AddrPreference::Ip4, Protocol::Ip4(_))
+                    | (AddrPreference::Ip6, Protocol::Ip6(_))
+            )
+        })
+    }
+}
+
+/// Pick the most preferred of `addrs`, comparing by the first preference
+/// first. Earlier addresses win ties.
+pub fn preferred_addr<'a>(
+    addrs: impl IntoIterator<Item = &'a Multiaddr>,
+    preferences: &[AddrPreference],
+) -> Option<&'a Multiaddr> {
+    addrs.into_iter().min_by_key(|addr| {
+        preferences
+            .iter()
+            .map(|preference| !preference.matches(addr))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Include and exclude CIDR filters deciding which observed addresses may be
+/// used as external addresses, e.g., to never hole punch via a VPN address.
+#[derive(Clone, Debug, Default)]
+pub struct AddrFilter {
+    pub include: Vec<IpNet>,
+    pub exclude: Vec<IpNet>,
+}
+
+impl AddrFilter {
+    /// Return true if `addr` is in one of the included networks, or no
+    /// networks are included, and in none of the excluded networks.
+    /// Addresses that do not start with an IP address are always allowed.
+    pub fn allows(&self, addr: &Multiaddr) -> bool {
+        let ip: IpAddr = match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => ip.into(),
+            Some(Protocol::Ip6(ip)) => ip.into(),
+            _ => return true,
+        };
+        (self.include.is_empty() || self.include.iter().any(|net| net.contains(&ip)))
+            && !self.exclude.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Pick the address reported most often in `observations`, preferring the
+/// earliest on ties, along with the number of distinct addresses reported.
+pub fn most_observed_addr(observations: &[Multiaddr]) -> Option<(Multiaddr, usize)> {
+    let mut counts: Vec<(&Multiaddr, usize)> = Vec::new();
+    for addr in observations {
+        match counts.iter_mut().find(|(a, _)| *a == addr) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((addr, 1)),
+        }
+    }
+    let distinct = counts.len();
+    // max_by_key returns the last maximum, so search from the back.
+    let (addr, _) = counts.into_iter().rev().max_by_key(|(_, count)| *count)?;
+    Some((addr.clone(), distinct))
+}
+
+/// Behaviours [`learn_external_address`] can run on, which tell identify and
+/// ping events apart from those of their other behaviours.
+pub trait IdentifyEvents: NetworkBehaviour {
+    /// Return the identify event in `event`, or `event` if it is another.
+    fn identify_event(event: Self::ToSwarm) -> Result<identify::Event, Self::ToSwarm>;
+
+    /// Return true if `event` is a ping event.
+    fn is_ping_event(event: &Self::ToSwarm) -> bool;
+}
+
+/// Connect to the relay at `relay_address`, and to the peer at
+/// `observer_address` if any, to learn our public address from identify,
+/// adding the address the relay observed most often over `observations`
+/// connections as an external address unless `filter` rejects it.
+pub async fn learn_external_address<B: IdentifyEvents>(
+    swarm: &mut Swarm<B>,
+    relay_address: Multiaddr,
+    observer_address: Option<Multiaddr>,
+    observations: NonZeroUsize,
+    filter: &AddrFilter,
+    event_handling: EventHandling,
+) -> Result<(), Box<dyn Error>>
+where
+    B::ToSwarm: std::fmt::Debug,
+{
+    // Connect to the relay server. Not for the reservation or relayed
+    // connection, but to (a) learn our local public address and (b) enable
+    // a freshly started relay to learn its public address.
+    swarm.dial(relay_address.clone())?;
+    let mut relay_observed_addrs = Vec::new();
+    let mut relay_observed_addr = None;
+    let mut told_relay_observed_addr = false;
+
+    // Optionally ask a second peer for our public address as well, to detect
+    // ALGs or multiple NATs rewriting addresses.
+    let observer = match &observer_address {
+        Some(address) => {
+            swarm.dial(address.clone())?;
+            Some(
+                peer_id_from_multiaddr(address)
+                    .ok_or("observer multiaddr must end with /p2p/<peer id>")?,
+            )
+        }
+        None => None,
+    };
+    let mut observer_observed_addr = None;
+
+    loop {
+        match swarm.next().await.unwrap() {
+            SwarmEvent::NewListenAddr { .. } => {}
+            SwarmEvent::Dialing { .. } => {}
+            SwarmEvent::ConnectionEstablished { .. } => {}
+            SwarmEvent::Behaviour(event) if B::is_ping_event(&event) => {}
+            SwarmEvent::Behaviour(event) => match B::identify_event(event) {
+                Ok(identify::Event::Sent { peer_id }) if Some(peer_id) != observer => {
+                    info!("Notified relay of its public address");
+                    told_relay_observed_addr = true;
+                }
+                Ok(identify::Event::Sent { .. }) => {}
+                Ok(identify::Event::Received {
+                    peer_id,
+                    info: identify::Info { observed_addr, .. },
+                }) if Some(peer_id) == observer => {
+                    info!("Observer says our public address is {}", observed_addr);
+                    observer_observed_addr = Some(observed_addr);
+                }
+                Ok(identify::Event::Received {
+                    info: identify::Info { observed_addr, .. },
+                    ..
+                }) => {
+                    info!("Relay says our public address is {}", observed_addr);
+                    relay_observed_addrs.push(observed_addr);
+                    if relay_observed_addrs.len() < observations.get() {
+                        // Each connection may leave through a different uplink,
+                        // so observe the address on a fresh connection.
+                        swarm.dial(
+                            DialOpts::unknown_peer_id()
+                                .address(relay_address.clone())
+                                .build(),
+                        )?;
+                        continue;
+                    }
+                    let (observed_addr, distinct) = most_observed_addr(&relay_observed_addrs)
+                        .expect("at least one address was observed");
+                    if distinct > 1 {
+                        warn!(
+                            "Relay observed {} different public addresses over {} connections, \
+                         using the most frequent {}",
+                            distinct,
+                            relay_observed_addrs.len(),
+                            observed_addr
+                        );
+                    }
+                    if filter.allows(&observed_addr) {
+                        swarm.add_external_address(observed_addr.clone());
+                    } else {
+                        warn!(
+                        "Not using {} as an external address: rejected by --external-addr-filter \
+                         or --external-addr-exclude",
+                        observed_addr
+                    );
+                    }
+                    relay_observed_addr = Some(observed_addr);
+                }
+                Ok(event) => event_handling.unexpected(event),
+                Err(event) => event_handling.unexpected(event),
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                let address = match peer_id == observer {
+                    true => observer_address.as_ref().unwrap_or(&relay_address),
+                    false => &relay_address,
+                };
+                return Err(format!("failed to dial {address}: {error}").into());
+            }
+            event => event_handling.unexpected(event),
+        }
+
+        let Some(relay_observed_addr) = relay_observed_addr.as_ref() else {
+            continue;
+        };
+        if !told_relay_observed_addr {
+            continue;
+        }
+        match (observer, observer_observed_addr.as_ref()) {
+            (None, _) => return Ok(()),
+            (Some(_), Some(observer_observed_addr)) => {
+                match compare_observed_addrs(relay_observed_addr, observer_observed_addr) {
+                    None => info!("Relay and observer agree on our public address"),
+                    Some(discrepancy) => warn!(
+                        "Relay and observer disagree on our public address ({}): relay {}, \
+                         observer {}; an ALG or multiple NATs may be rewriting addresses",
+                        discrepancy, relay_observed_addr, observer_observed_addr
+                    ),
+                }
+                return Ok(());
+            }
+            (Some(_), None) => {}
+        }
+    }
+}
+
+/// Return true if `addr` starts with a loopback IP address or `localhost`.
+pub fn is_loopback(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+        Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+        Some(Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host)) => {
+            host == "localhost"
+        }
+        _ => false,
+    }
+}
+
+/// Return true if `addr` starts with an IP address that is reachable from the
+/// public internet, i.e., not loopback, private, link-local or unspecified.
+pub fn is_public(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => {
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified())
+        }
+        Some(Protocol::Ip6(ip)) => {
+            let unique_local = (ip.segments()[0] & 0xfe00) == 0xfc00;
+            let link_local = (ip.segments()[0] & 0xffc0) == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+        }
+        _ => false,
+    }
+}
+
+/// Write a ticket containing the receiver's circuit address, e.g.
+/// `/ip4/1.2.3.4/tcp/4001/p2p/<relay>/p2p-circuit/p2p/<receiver>`, which
+/// senders can read to find the receiver.
+///
+/// The ticket is written to a temporary file and renamed so readers never
+/// see a partial ticket.
+pub fn write_ticket(path: &Path, circuit_addr: &Multiaddr) -> Result<(), Box<dyn Error>> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, format!("{circuit_addr}\n"))?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Read a ticket written by [`write_ticket`].
+pub fn read_ticket(path: &Path) -> Result<Multiaddr, Box<dyn Error>> {
+    let ticket = std::fs::read_to_string(path)?;
+    let addr: Multiaddr = ticket
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .ok_or_else(|| format!("ticket {} is empty", path.display()))?
+        .trim()
+        .parse()?;
+    if peer_id_from_multiaddr(&addr).is_none() || !addr.iter().any(|p| p == Protocol::P2pCircuit) {
+        return Err(format!("ticket {} is not a circuit address: {addr}", path.display()).into());
+    }
+    Ok(addr)
+}
+
+/// Authenticated and multiplexed transport built by [`build_transport`].
+pub type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+/// Number of keepalive intervals after which connections without active
+/// streams are closed with `--keepalive-interval`, so keepalives alone keep
+/// them open.
+pub const KEEPALIVE_IDLE_INTERVALS: u32 = 3;
+
+/// Settings of the transport stack built by [`build_transport`].
+pub struct TransportOptions {
+    pub tcp_config: tcp::Config,
+    /// Keep `TCP_QUICKACK` set on TCP connections (Linux only).
+    pub tcp_quickack: bool,
+    /// Interval of TCP keepalive probes and QUIC keep-alive packets on idle
+    /// connections. QUIC connections time out after
+    /// [`KEEPALIVE_IDLE_INTERVALS`] intervals instead of libp2p-quic's
+    /// default.
+    pub keepalive_interval: Option<Duration>,
+    /// Secure relayed and TCP connections with libp2p's plaintext protocol
+    /// instead of noise. Must only be used on loopback.
+    pub insecure_plaintext: bool,
+    /// Fail outbound dials, including circuit dials through a relay, if the
+    /// connection is not established and upgraded in time.
+    pub dial_timeout: Option<Duration>,
+}
+
+/// Build the relay client, TCP, and QUIC transport stack shared by the
+/// benchmark binaries. Relayed and TCP connections are secured with noise
+/// and multiplexed with yamux.
+pub async fn build_transport(
+    local_key: &Keypair,
+    relay_transport: relay::client::Transport,
+    options: TransportOptions,
+) -> Result<BoxedTransport, Box<dyn Error>> {
+    build_transport_with(local_key, relay_transport, options, |transport| transport).await
+}
+
+/// Like [`build_transport`], but wraps the final transport with
+/// `middleware`, e.g., a rate limiter, fault injector, or recorder
+/// implementing [`Transport`], so experiments can instrument every
+/// connection without forking the transport stack.
+pub async fn build_transport_with(
+    local_key: &Keypair,
+    relay_transport: relay::client::Transport,
+    options: TransportOptions,
+    middleware: impl FnOnce(BoxedTransport) -> BoxedTransport,
+) -> Result<BoxedTransport, Box<dyn Error>> {
+    let TransportOptions {
+        tcp_config,
+        tcp_quickack,
+        keepalive_interval,
+        insecure_plaintext,
+        dial_timeout,
+    } = options;
+    if (tcp_quickack || keepalive_interval.is_some()) && !cfg!(target_os = "linux") {
+        return Err("TCP_QUICKACK and TCP keepalive are only supported on Linux".into());
+    }
+    let tcp_transport = tcp::async_io::Transport::new(tcp_config).map(move |stream, _| {
+        if let Some(interval) = keepalive_interval {
+            if let Err(e) = set_tcp_keepalive(&stream, interval) {
+                warn!("Failed to enable TCP keepalive: {e}");
+            }
+        }
+        QuickAck::new(stream, tcp_quickack)
+    });
+    let relay_tcp_transport = relay_transport.or_transport(tcp_transport);
+
+    let relay_tcp_transport = if insecure_plaintext {
+        warn!("Using INSECURE plaintext security for relayed and TCP connections");
+        relay_tcp_transport
+            .upgrade(upgrade::Version::V1)
+            .authenticate(plaintext::Config::new(local_key))
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed()
+    } else {
+        relay_tcp_transport
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::Config::new(local_key)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed()
+    };
+
+    let mut quic_config = quic::Config::new(local_key);
+    if let Some(interval) = keepalive_interval {
+        quic_config.keep_alive_interval = interval;
+        quic_config.max_idle_timeout =
+            u32::try_from((interval * KEEPALIVE_IDLE_INTERVALS).as_millis()).unwrap_or(u32::MAX);
+    }
+    let relay_tcp_quic_transport =
+        relay_tcp_transport.or_transport(quic::async_std::Transport::new(quic_config));
+
+    let transport = dns::async_std::Transport::system(relay_tcp_quic_transport)
+        .await?
+        .map(|either_output, _| match either_output {
+            Either::Left((peer_id, muxer)) => (peer_id, muxer),
+            Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+        });
+
+    let transport = match dial_timeout {
+        Some(timeout) => TransportTimeout::with_outgoing_timeout(transport, timeout).boxed(),
+        None => transport.boxed(),
+    };
+    Ok(middleware(transport))
+}
+
+/// Send TCP keepalive probes on `stream` after `interval` of inactivity and
+/// every `interval` thereafter.
+#[cfg(target_os = "linux")]
+fn set_tcp_keepalive(stream: &tcp::async_io::TcpStream, interval: Duration) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    let secs = interval.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+    for (level, name, value) in [
+        (libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1),
+        (libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs),
+        (libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, secs),
+    ] {
+        // SAFETY: the option value points to a c_int of the given size that
+        // outlives the call.
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tcp_keepalive(
+    _stream: &tcp::async_io::TcpStream,
+    _interval: Duration,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "TCP keepalive intervals are only supported on Linux",
+    ))
+}
+
 pub async fn swarm_listen<B: NetworkBehaviour>(
     swarm: &mut Swarm<B>,
     transport: TransportMethod,
+    wait: Duration,
+    event_handling: EventHandling,
 ) -> Result<(), Box<dyn Error>>
 where
     <B as NetworkBehaviour>::ToSwarm: std::fmt::Debug,
@@ -36,7 +1214,7 @@ where
     swarm.listen_on(listen_address)?;
 
     // Wait to listen on all interfaces.
-    let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
+    let mut delay = futures_timer::Delay::new(wait).fuse();
     loop {
         futures::select! {
             event = swarm.next() => {
@@ -44,7 +1222,7 @@ where
                     SwarmEvent::NewListenAddr { address, .. } => {
                         info!("Listening on {}", address);
                     }
-                    event => panic!("{event:?}"),
+                    event => event_handling.unexpected(event),
                 }
             }
             _ = delay => {
@@ -54,3 +1232,163 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("12B"), Ok(12));
+        assert_eq!(parse_size("1KB"), Ok(1_000));
+        assert_eq!(parse_size("1kB"), Ok(1_000));
+        assert_eq!(parse_size("250MB"), Ok(250_000_000));
+        assert_eq!(parse_size("2GB"), Ok(2_000_000_000));
+        assert_eq!(parse_size("1KiB"), Ok(1024));
+        assert_eq!(parse_size("1MiB"), Ok(1 << 20));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("1.5MB"), Ok(1_500_000));
+        assert_eq!(parse_size(" 10 MB "), Ok(10_000_000));
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("10XB").is_err());
+        assert!(parse_size("1.5B").is_err());
+        assert!(parse_size("-1MB").is_err());
+        assert!(parse_size(".").is_err());
+        assert!(parse_size("1.2.3MB").is_err());
+        assert!(parse_size("1.0001KB").is_err());
+    }
+
+    #[test]
+    fn parse_size_fractions_are_exact() {
+        assert_eq!(parse_size("1.001KB"), Ok(1_001));
+        assert_eq!(parse_size("0.000123MB"), Ok(123));
+        assert_eq!(parse_size(".5KB"), Ok(500));
+        assert_eq!(parse_size("1.50000KB"), Ok(1_500));
+        assert_eq!(parse_size("0.5KiB"), Ok(512));
+        for millis in 0..1000 {
+            assert_eq!(parse_size(&format!("7.{millis:03}KB")), Ok(7_000 + millis));
+        }
+    }
+
+    #[test]
+    fn parse_size_rejects_overflow() {
+        assert_eq!(parse_size(&usize::MAX.to_string()), Ok(usize::MAX));
+        assert!(parse_size(&(usize::MAX as u128 + 1).to_string()).is_err());
+        assert!(parse_size("18446744073709552KB").is_err());
+        assert!(parse_size("16777216TiB").is_err());
+        assert!(parse_size("16777215.99999999999999999999999999999999999TiB").is_err());
+    }
+
+    fn addr_filter(include: &[&str], exclude: &[&str]) -> AddrFilter {
+        AddrFilter {
+            include: include.iter().map(|net| net.parse().unwrap()).collect(),
+            exclude: exclude.iter().map(|net| net.parse().unwrap()).collect(),
+        }
+    }
+
+    fn allows(filter: &AddrFilter, addr: &str) -> bool {
+        filter.allows(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn addr_filter_without_networks_allows_all() {
+        let filter = addr_filter(&[], &[]);
+        assert!(allows(&filter, "/ip4/10.0.0.1/tcp/4001"));
+        assert!(allows(&filter, "/ip6/::1/udp/4001/quic-v1"));
+    }
+
+    #[test]
+    fn addr_filter_include() {
+        let filter = addr_filter(&["10.0.0.0/8", "fd00::/8"], &[]);
+        assert!(allows(&filter, "/ip4/10.1.2.3/tcp/4001"));
+        assert!(allows(&filter, "/ip6/fd12::1/udp/4001/quic-v1"));
+        assert!(!allows(&filter, "/ip4/192.168.1.1/tcp/4001"));
+        assert!(!allows(&filter, "/ip6/2001:db8::1/tcp/4001"));
+    }
+
+    #[test]
+    fn addr_filter_exclude_wins_over_include() {
+        let filter = addr_filter(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(allows(&filter, "/ip4/10.2.0.1/tcp/4001"));
+        assert!(!allows(&filter, "/ip4/10.1.0.1/tcp/4001"));
+
+        let filter = addr_filter(&[], &["172.16.0.0/12"]);
+        assert!(allows(&filter, "/ip4/8.8.8.8/tcp/4001"));
+        assert!(!allows(&filter, "/ip4/172.17.0.1/tcp/4001"));
+    }
+
+    #[test]
+    fn addr_filter_allows_non_ip_addresses() {
+        let filter = addr_filter(&["10.0.0.0/8"], &["0.0.0.0/0", "::/0"]);
+        assert!(allows(&filter, "/dns4/example.com/tcp/4001"));
+        assert!(!allows(&filter, "/ip4/10.0.0.1/tcp/4001"));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5), 5);
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_delay()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs).to_vec());
+        assert_eq!(backoff.retries(), 5);
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn backoff_reset() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1), 2);
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(200)));
+        assert_eq!(backoff.next_delay(), None);
+
+        backoff.reset();
+        assert_eq!(backoff.retries(), 0);
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn backoff_without_retries() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(1), 0);
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.retries(), 0);
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn expand_preset_inserts_after_program_name() {
+        let args = strings(&["send", "--preset", "smoke", "--runs", "3"]);
+        let mut expected = strings(&["send"]);
+        expected.extend(strings(Preset::Smoke.args()));
+        expected.extend(strings(&["--preset", "smoke", "--runs", "3"]));
+        assert_eq!(expand_preset(args), expected);
+    }
+
+    #[test]
+    fn expand_preset_with_equals() {
+        let args = strings(&["send", "--runs", "3", "--preset=smoke"]);
+        let mut expected = strings(&["send"]);
+        expected.extend(strings(Preset::Smoke.args()));
+        expected.extend(strings(&["--runs", "3", "--preset=smoke"]));
+        assert_eq!(expand_preset(args), expected);
+    }
+
+    #[test]
+    fn expand_preset_leaves_other_args_alone() {
+        let args = strings(&["send", "--runs", "3"]);
+        assert_eq!(expand_preset(args.clone()), args);
+
+        let args = strings(&["send", "--preset", "nonexistent"]);
+        assert_eq!(expand_preset(args.clone()), args);
+
+        let args = strings(&["send", "--preset"]);
+        assert_eq!(expand_preset(args.clone()), args);
+    }
+}
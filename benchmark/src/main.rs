@@ -0,0 +1,55 @@
+use std::error::Error;
+
+use clap::{Parser, Subcommand};
+
+use benchmark::{expand_preset, receive, selftest, send, BuildInfo};
+
+#[derive(Parser, Debug)]
+#[command(name = "p2p-bench", author, version, about, long_about = None, propagate_version = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Hole punch to a receiver via the relay and run benchmarks over the
+    /// direct connection.
+    #[command(args_override_self = true)]
+    Send(Box<send::Args>),
+    /// Reserve a slot on the relay and serve benchmark runs of senders.
+    Receive(Box<receive::Args>),
+    /// Run a relay server.
+    Relay(relay_server::Opt),
+    /// Run a receiver and a sender in this process over loopback and print
+    /// the throughput as a baseline for field experiments.
+    Perf(selftest::Args),
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format_timestamp_millis()
+        .init();
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let behaviours = match args.get(1).map(String::as_str) {
+        Some("send") => send::BEHAVIOURS,
+        Some("receive") => receive::BEHAVIOURS,
+        Some("relay") => &["relay", "ping", "identify"],
+        Some("perf") => selftest::BEHAVIOURS,
+        _ => &[],
+    };
+    BuildInfo::new(env!("CARGO_BIN_NAME"), behaviours).print_if_requested(&args)?;
+    if args.get(1).is_some_and(|command| command == "send") {
+        // Insert the preset's arguments after the subcommand, so explicit
+        // arguments still override them.
+        let preset_args = expand_preset(args.split_off(1));
+        args.extend(preset_args);
+    }
+    match Args::parse_from(args).command {
+        Command::Send(args) => send::main(*args),
+        Command::Receive(args) => receive::main(*args),
+        Command::Relay(opt) => relay_server::run(opt),
+        Command::Perf(args) => selftest::main(args),
+    }
+}
@@ -0,0 +1,137 @@
+use std::error::Error;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+use benchmark::{parse_size, BuildInfo, TransportMethod};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print one `p2p-bench send` invocation per combination of the given
+    /// transports, TCP_QUICKACK settings, payloads and run counts.
+    Generate(GenerateArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    // Transports to sweep (e.g., tcp,quic-v1). The muxer follows from the
+    // transport: yamux over TCP and QUIC's native streams over QUIC.
+    #[arg(long, value_enum, value_delimiter = ',', required = true)]
+    transport: Vec<TransportMethod>,
+
+    // TCP_QUICKACK settings to sweep for TCP transports (e.g., false,true).
+    // With --transport tcp,tcp-no-delay, this sweeps the Nagle and delayed
+    // ACK combinations.
+    #[arg(long, value_delimiter = ',', default_value = "false")]
+    tcp_quickack: Vec<bool>,
+
+    // Payload sizes to sweep (e.g., 1MB,100MB).
+    #[arg(long, value_delimiter = ',', value_parser = parse_payload, required = true)]
+    payload: Vec<String>,
+
+    // Run counts to sweep (e.g., 1,10).
+    #[arg(long, value_delimiter = ',', default_value = "1")]
+    runs: Vec<usize>,
+
+    // Print shell command lines or one JSON scenario per line.
+    #[arg(long, value_enum, default_value_t = MatrixFormat::Shell)]
+    format: MatrixFormat,
+
+    // Arguments appended to every `p2p-bench send` invocation, e.g.,
+    // `-- --relay-multiaddr <addr> --receiver-peer-id <id>`.
+    #[arg(last = true)]
+    sender_args: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum MatrixFormat {
+    Shell,
+    Json,
+}
+
+/// A single point of the sweep.
+#[derive(Debug, Serialize)]
+struct Scenario {
+    transport: TransportMethod,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp_quickack: Option<bool>,
+    payload: String,
+    runs: usize,
+    args: Vec<String>,
+}
+
+fn parse_payload(s: &str) -> Result<String, String> {
+    parse_size(s).map(|_| s.to_string())
+}
+
+/// Quote `arg` for a POSIX shell if it contains anything but safe characters.
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+fn generate(args: GenerateArgs) -> Result<(), Box<dyn Error>> {
+    for transport in &args.transport {
+        let quickack_settings: Vec<Option<bool>> = match transport {
+            TransportMethod::QuicV1 => vec![None],
+            _ => args.tcp_quickack.iter().copied().map(Some).collect(),
+        };
+        for tcp_quickack in quickack_settings {
+            for payload in &args.payload {
+                for runs in &args.runs {
+                    let transport_name = transport
+                        .to_possible_value()
+                        .expect("no skipped variants")
+                        .get_name()
+                        .to_string();
+                    let mut scenario_args = vec!["--transport".to_string(), transport_name];
+                    if tcp_quickack == Some(true) {
+                        scenario_args.push("--tcp-quickack".to_string());
+                    }
+                    scenario_args.extend([
+                        "--payload".to_string(),
+                        payload.clone(),
+                        "--runs".to_string(),
+                        runs.to_string(),
+                    ]);
+                    scenario_args.extend(args.sender_args.iter().cloned());
+                    let scenario = Scenario {
+                        transport: *transport,
+                        tcp_quickack,
+                        payload: payload.clone(),
+                        runs: *runs,
+                        args: scenario_args,
+                    };
+                    match args.format {
+                        MatrixFormat::Shell => {
+                            let quoted: Vec<_> =
+                                scenario.args.iter().map(|arg| shell_quote(arg)).collect();
+                            println!("p2p-bench send {}", quoted.join(" "));
+                        }
+                        MatrixFormat::Json => println!("{}", serde_json::to_string(&scenario)?),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    BuildInfo::new(env!("CARGO_BIN_NAME"), &[]).print_if_requested(&args)?;
+    match Args::parse_from(args).command {
+        Command::Generate(args) => generate(args),
+    }
+}
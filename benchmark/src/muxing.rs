@@ -0,0 +1,61 @@
+//! A muxer that passes every substream of a connection through a hook, so
+//! streams can be instrumented or shaped by wrapping them, e.g., to count
+//! write stalls or time transfers, without reimplementing the muxer.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+
+/// Wrap the muxer of a connection so `hook` is applied to each of its
+/// inbound and outbound substreams.
+pub fn wrap_substreams<F>(muxer: StreamMuxerBox, hook: F) -> StreamMuxerBox
+where
+    F: FnMut(SubstreamBox) -> SubstreamBox + Send + Unpin + 'static,
+{
+    StreamMuxerBox::new(WrappingMuxer { inner: muxer, hook })
+}
+
+struct WrappingMuxer<F> {
+    inner: StreamMuxerBox,
+    hook: F,
+}
+
+impl<F> StreamMuxer for WrappingMuxer<F>
+where
+    F: FnMut(SubstreamBox) -> SubstreamBox + Unpin,
+{
+    type Substream = SubstreamBox;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let inner = ready!(Pin::new(&mut this.inner).poll_inbound(cx)?);
+        Poll::Ready(Ok((this.hook)(inner)))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let inner = ready!(Pin::new(&mut this.inner).poll_outbound(cx)?);
+        Poll::Ready(Ok((this.hook)(inner)))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll(cx)
+    }
+}
@@ -0,0 +1,113 @@
+//! TCP streams that keep Linux's `TCP_QUICKACK` set, i.e., acknowledge every
+//! segment right away instead of delaying ACKs. The kernel clears the option
+//! again on its own, so it is set after every read.
+
+use std::io;
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::io::{IoSlice, IoSliceMut};
+use futures::prelude::*;
+use futures::ready;
+use log::debug;
+
+/// A stream that sets `TCP_QUICKACK` after every read if `enabled`.
+pub struct QuickAck<S> {
+    inner: S,
+    enabled: bool,
+}
+
+impl<S: AsRawFd> QuickAck<S> {
+    pub fn new(inner: S, enabled: bool) -> Self {
+        let mut stream = QuickAck { inner, enabled };
+        stream.rearm();
+        stream
+    }
+
+    fn rearm(&mut self) {
+        if self.enabled {
+            if let Err(e) = set_quickack(self.inner.as_raw_fd()) {
+                debug!("Failed to set TCP_QUICKACK: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_quickack(fd: std::os::fd::RawFd) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    // SAFETY: the option value points to a c_int of the given size that
+    // outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_QUICKACK,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_quickack(_fd: std::os::fd::RawFd) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_QUICKACK is only supported on Linux",
+    ))
+}
+
+impl<S: AsyncRead + AsRawFd + Unpin> AsyncRead for QuickAck<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.rearm();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_read_vectored(cx, bufs))?;
+        this.rearm();
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for QuickAck<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
@@ -1,37 +1,279 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
-use futures::{future::Either, StreamExt};
+use futures::{FutureExt, StreamExt};
+use ipnet::IpNet;
 use libp2p::{
-    core::{
-        multiaddr::{Multiaddr, Protocol},
-        muxing::StreamMuxerBox,
-        transport::Transport,
-        upgrade,
-    },
-    dcutr, dns, identify, noise, ping, quic, relay,
-    swarm::{NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent},
-    tcp, yamux, PeerId,
+    bandwidth::BandwidthSinks,
+    core::multiaddr::{Multiaddr, Protocol},
+    dcutr, identify,
+    identity::Keypair,
+    ping, relay, request_response,
+    swarm::{self, behaviour::toggle::Toggle, NetworkBehaviour, Swarm, SwarmEvent},
+    tcp, PeerId, Transport, TransportExt,
 };
-use log::info;
+use log::{debug, info, warn};
 
-use benchmark::{generate_ed25519, swarm_listen, TransportMethod};
+use crate::{
+    admin::AdminSocket,
+    audit::AuditLog,
+    build_transport_with, configure_runtime, format_bytes, generate_ed25519, is_loopback,
+    is_rate_limited, learn_external_address, open_fds, parse_cpu_list, parse_duration, parse_rate,
+    parse_size, peer_id_from_multiaddr, resident_set_size,
+    results::ResultsDir,
+    signal,
+    stalls::{StallStats, WriteStalls},
+    stress, swarm_listen,
+    throttle::ReadThrottle,
+    transfers::{Transfer, TransferTimings},
+    write_ticket, AddrFilter, Backoff, Capabilities, CommonArgs, CpuList, EventHandling,
+    IdentifyEvents, TransportMethod, TransportOptions, KEEPALIVE_IDLE_INTERVALS,
+};
+
+/// Time to wait for further local address changes before reconnecting to
+/// the relay, so a roam is handled once.
+const NETWORK_SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// Ping interval with --backup-relay, so a lost relay is noticed within a
+/// few seconds.
+const RELAY_PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Behaviours of the receiver, reported by `--version --json`.
+pub const BEHAVIOURS: &[&str] = &[
+    "relay-client",
+    "ping",
+    "identify",
+    "dcutr",
+    "perf-server",
+    "signal",
+];
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    // Also ask this peer (e.g., a second relay server) for our public
+    // address and flag disagreements with the relay's observation.
+    #[arg(long)]
+    observer: Option<Multiaddr>,
+
+    // Number of connections to the relay over which our public address is
+    // observed before the most frequent observation is used, e.g., behind
+    // multi-WAN routers where connections may leave through different
+    // uplinks.
+    #[arg(long, default_value = "1")]
+    address_observations: NonZeroUsize,
+
+    // Only use observed addresses in this network as external addresses
+    // (e.g., 203.0.113.0/24). Repeatable.
+    #[arg(long)]
+    external_addr_filter: Vec<IpNet>,
+
+    // Never use observed addresses in this network as external addresses,
+    // e.g., a VPN's network (e.g., 10.8.0.0/16). Repeatable.
+    #[arg(long)]
+    external_addr_exclude: Vec<IpNet>,
+
+    // Time to wait for the swarm to listen on all interfaces (e.g., 1s).
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    listen_wait: Duration,
+
+    // Use plaintext instead of noise for relayed and TCP connections to
+    // isolate the cost of the security layer. INSECURE: only permitted
+    // when the relay is on loopback.
+    #[arg(long)]
+    insecure_plaintext: bool,
+
+    // Keep TCP_QUICKACK set on TCP connections, i.e., acknowledge every
+    // segment right away instead of delaying ACKs (Linux only). Combine with
+    // --transport tcp-no-delay to disable Nagle's algorithm as well.
+    #[arg(long)]
+    tcp_quickack: bool,
+
+    // Interval of keepalives on idle connections (e.g., 25s): libp2p pings,
+    // TCP keepalive probes (Linux only), and QUIC keep-alive packets.
+    // Connections without active streams stay open for three intervals.
+    // Defaults to libp2p's 15s pings and QUIC keep-alives without TCP
+    // keepalive probes.
+    #[arg(long, value_parser = parse_duration)]
+    keepalive_interval: Option<Duration>,
+
+    // Number of addresses of a peer dialed concurrently.
+    #[arg(long, default_value = "8")]
+    dial_concurrency_factor: NonZeroU8,
+
+    // Timeout for establishing and upgrading an outbound connection,
+    // including circuit dials through the relay (e.g., 30s).
+    #[arg(long, value_parser = parse_duration)]
+    dial_timeout: Option<Duration>,
+
+    // Initial delay before retrying a request the relay refused because one
+    // of its limits was exceeded. Doubles on every retry (e.g., 1s).
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    rate_limit_backoff: Duration,
+
+    // Largest delay between retries of rate limited requests (e.g., 1m).
+    #[arg(long, value_parser = parse_duration, default_value = "1m")]
+    rate_limit_max_backoff: Duration,
+
+    // Number of retries of a rate limited request before giving up.
+    #[arg(long, default_value_t = 5)]
+    rate_limit_retries: usize,
+
+    // Panic on unexpected swarm events (strict) or log them and continue
+    // (lenient), e.g., for long campaigns.
+    #[arg(long, value_enum, default_value_t=EventHandling::Strict)]
+    event_handling: EventHandling,
+
+    // Number of async runtime worker threads (defaults to the number of CPUs).
+    #[arg(long)]
+    worker_threads: Option<usize>,
+
+    // CPUs to pin the process to (e.g., 0,2-3). Linux only.
+    #[arg(long, value_parser = parse_cpu_list)]
+    cpu_affinity: Option<CpuList>,
+
+    // Stop accepting new runs after this long (e.g., 2h), cancel the relay
+    // reservation and exit once in-flight runs have finished.
+    #[arg(long, value_parser = parse_duration)]
+    available_window: Option<Duration>,
+
+    // Only hold and renew the reservation on the relay and keep the ticket
+    // current, without serving perf runs, e.g., to keep the receiver
+    // reachable at little cost. Run plans are rejected.
+    #[arg(long, conflicts_with_all = ["results_dir", "read_rate"])]
+    reserve_only: bool,
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    // Seed used to generate deterministic peer id.
-    #[arg(short, long)]
-    seed: u8,
+    // Serve the status of the relay reservation as a line of JSON to every
+    // connection to this Unix socket.
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
 
-    // Relay server multi-address.
-    #[arg(short, long)]
-    relay_multiaddr: Multiaddr,
+    // Largest payload size senders may request, advertised to senders via
+    // identify (e.g., 1GiB).
+    #[arg(long, value_parser = parse_size, default_value = "10GiB")]
+    max_payload: usize,
 
-    // Transport method (tcp or quic-v1).
-    // Should match the transport method of relay_multiaddr.
-    #[arg(short, long, value_enum, default_value_t=TransportMethod::Tcp)]
-    transport: TransportMethod,
+    // Validate the configuration, resolve and dial the relay, and print the
+    // plan without requesting a reservation.
+    #[arg(long)]
+    dry_run: bool,
+
+    // Record connection opens, closes, and rejections with peer ids,
+    // endpoints, and byte totals to this file, independent of the log level.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    // Write the receiver's timing of each throughput run of each sender to
+    // <dir>/<sender peer id>/<run id>.json, e.g., for throwaway senders that
+    // cannot persist their own results.
+    #[arg(long)]
+    results_dir: Option<PathBuf>,
+
+    // Size at which the audit log is rotated (e.g., 10MB).
+    #[arg(long, value_parser = parse_size, default_value = "10MB")]
+    audit_log_max_size: usize,
+
+    // Number of rotated audit logs to keep.
+    #[arg(long, default_value_t = 5)]
+    audit_log_files: usize,
+
+    // Reject run plans from a sender whose previous plan was accepted, or
+    // that disconnected, less than this long ago (e.g., 30s), so a sender
+    // retrying in a tight loop cannot monopolize a shared receiver.
+    #[arg(long, value_parser = parse_duration)]
+    min_run_interval: Option<Duration>,
+
+    // Refuse new runs while the receiver's resident set size exceeds this
+    // (e.g., 1GiB). Linux only.
+    #[arg(long, value_parser = parse_size)]
+    max_rss: Option<usize>,
+
+    // Read from all streams at most at this rate (e.g., 1MBps) to check that
+    // backpressure reaches the sender without unbounded buffering. The
+    // receiver's peak resident set size while reads waited is logged and
+    // reported to senders with --cross-check (Linux only).
+    #[arg(long, value_parser = parse_rate)]
+    read_rate: Option<usize>,
+
+    // Refuse new runs while the receiver has more file descriptors open than
+    // this. Linux only.
+    #[arg(long)]
+    max_fds: Option<usize>,
+
+    // Refuse new runs while this many other senders are connected. The perf
+    // server does not report streams, so each connected sender counts as one
+    // run.
+    #[arg(long)]
+    max_concurrent_runs: Option<usize>,
+
+    // Also hold a reservation on this relay so senders can compare hole
+    // punching via different relays (see the sender's --compare-relay).
+    // Repeatable.
+    #[arg(long)]
+    extra_relay: Vec<Multiaddr>,
+
+    // Request a reservation on this relay once the connection to the relay
+    // is lost, e.g., to measure how quickly the receiver fails over when the
+    // relay is killed mid-session.
+    #[arg(long)]
+    backup_relay: Option<Multiaddr>,
+
+    // Write the receiver's circuit address to this file once the relay
+    // accepted the reservation, for senders started with --ticket-file.
+    #[arg(long)]
+    ticket_file: Option<PathBuf>,
+
+    // Interval at which identify information is exchanged with connected
+    // peers, which also has the relay re-confirm our public address (e.g.,
+    // 1m).
+    #[arg(long, value_parser = parse_duration, default_value = "5m")]
+    identify_interval: Duration,
+
+    // Forget a public address learned from the relay once the relay has not
+    // confirmed it for this long (e.g., 10m), e.g., behind carrier-grade NATs
+    // whose mappings expire within minutes. Must exceed --identify-interval.
+    #[arg(long, value_parser = parse_duration)]
+    external_address_ttl: Option<Duration>,
+
+    // When local interface addresses change (e.g., a laptop roaming between
+    // Wi-Fi networks), forget our public addresses and reconnect to the relay
+    // so it observes the new one.
+    #[arg(long)]
+    follow_network_changes: bool,
+
+    // Rotate to a fresh random identity this often (e.g., 1h), once no runs
+    // are in flight, to test how senders' discovery and caches cope with
+    // identity churn. The new identity reserves on the relay before the old
+    // one is dropped, and the ticket is rewritten with the new peer id.
+    #[arg(long, value_parser = parse_duration, requires = "ticket_file")]
+    rotate_identity: Option<Duration>,
+
+    // Allow load patterns that put significant load on the relay, such as
+    // --stress-reservations. Only use them against relays you operate.
+    #[arg(long)]
+    stress: bool,
+
+    // Also hold reservations on the relay with this many additional random
+    // identities, to scale test the relay's reservation storage. Requires
+    // --stress.
+    #[arg(long, requires = "stress")]
+    stress_reservations: Option<NonZeroUsize>,
+}
+
+impl Args {
+    /// Filter of observed addresses usable as external addresses.
+    fn addr_filter(&self) -> AddrFilter {
+        AddrFilter {
+            include: self.external_addr_filter.clone(),
+            exclude: self.external_addr_exclude.clone(),
+        }
+    }
 }
 
 #[derive(NetworkBehaviour)]
@@ -40,61 +282,781 @@ struct Behaviour {
     ping: ping::Behaviour,
     identify: identify::Behaviour,
     dcutr: dcutr::Behaviour,
-    perf: libp2p_perf::server::Behaviour,
+    perf: Toggle<libp2p_perf::server::Behaviour>,
+    signal: signal::Behaviour,
 }
 
-#[async_std::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format_timestamp_millis()
-        .init();
+impl IdentifyEvents for Behaviour {
+    fn identify_event(event: BehaviourEvent) -> Result<identify::Event, BehaviourEvent> {
+        match event {
+            BehaviourEvent::Identify(event) => Ok(event),
+            event => Err(event),
+        }
+    }
+
+    fn is_ping_event(event: &BehaviourEvent) -> bool {
+        matches!(event, BehaviourEvent::Ping(_))
+    }
+}
 
-    let args = Args::parse();
+/// Run the receiver until it fails or is interrupted.
+pub fn main(args: Args) -> Result<(), Box<dyn Error>> {
+    configure_runtime(args.worker_threads, args.cpu_affinity.as_ref())?;
+    async_std::task::block_on(run(args))
+}
 
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
     info!("DCUTR Bandwidth Benchmark: Receiver");
-    info!("Relay multiaddr: {}", args.relay_multiaddr);
-    info!("Transport method: {:?}", args.transport);
+    info!("Relay multiaddr: {}", args.common.relay_multiaddr);
+    info!("Transport method: {:?}", args.common.transport);
+    if let Some(window) = args.available_window {
+        info!("Available for: {}", humantime::format_duration(window));
+    }
+
+    let relay_peer_id = peer_id_from_multiaddr(&args.common.relay_multiaddr)
+        .ok_or("relay multiaddr must end with /p2p/<peer id>")?;
+    let mut relay_peer_ids = vec![relay_peer_id];
+    for relay in &args.extra_relay {
+        relay_peer_ids.push(
+            peer_id_from_multiaddr(relay)
+                .ok_or("extra relay multiaddr must end with /p2p/<peer id>")?,
+        );
+    }
+    let backup_relay_peer_id = match &args.backup_relay {
+        Some(relay) => Some(
+            peer_id_from_multiaddr(relay)
+                .ok_or("backup relay multiaddr must end with /p2p/<peer id>")?,
+        ),
+        None => None,
+    };
+    relay_peer_ids.extend(backup_relay_peer_id);
+    // Reservations are told apart by relay, so a relay given twice would be
+    // taken for the wrong role, e.g., a backup accepted before the relay was
+    // lost.
+    for (i, peer_id) in relay_peer_ids.iter().enumerate() {
+        if relay_peer_ids[..i].contains(peer_id) {
+            return Err(format!(
+                "relay {peer_id} is given more than once across --relay-multiaddr, \
+                 --extra-relay and --backup-relay"
+            )
+            .into());
+        }
+    }
+
+    // Fail early where resource usage cannot be measured.
+    if args.max_rss.is_some() {
+        resident_set_size()?;
+    }
+    if args.max_fds.is_some() {
+        open_fds()?;
+    }
+
+    if let Some(ttl) = args.external_address_ttl {
+        if ttl <= args.identify_interval {
+            return Err(format!(
+                "--external-address-ttl of {} must exceed --identify-interval of {}",
+                humantime::format_duration(ttl),
+                humantime::format_duration(args.identify_interval)
+            )
+            .into());
+        }
+    }
+
+    if args.insecure_plaintext {
+        if !is_loopback(&args.common.relay_multiaddr) {
+            return Err("--insecure-plaintext requires a loopback relay multiaddr".into());
+        }
+        if matches!(args.common.transport, TransportMethod::QuicV1) {
+            return Err("--insecure-plaintext is not supported with QUIC".into());
+        }
+    }
+
+    if args.tcp_quickack && matches!(args.common.transport, TransportMethod::QuicV1) {
+        return Err("--tcp-quickack is not supported with QUIC".into());
+    }
 
-    let mut tcp_config = match args.transport {
+    let mut tcp_config = match args.common.transport {
         TransportMethod::TcpNoDelay => tcp::Config::default().nodelay(true),
         TransportMethod::Tcp => tcp::Config::default().nodelay(false),
         _ => tcp::Config::default(),
     };
     tcp_config = tcp_config.port_reuse(true);
 
-    let mut swarm = build_swarm(args.seed, tcp_config).await?;
-    swarm_listen(&mut swarm, args.transport).await?;
-    learn_external_address(&mut swarm, args.relay_multiaddr.clone()).await?;
+    let mut audit_log = match &args.audit_log {
+        Some(path) => Some(AuditLog::open(
+            path,
+            args.audit_log_max_size as u64,
+            args.audit_log_files,
+        )?),
+        None => None,
+    };
+
+    let write_stalls = WriteStalls::default();
+    let transfers = TransferTimings::default();
+    let read_throttle = args.read_rate.map(ReadThrottle::new);
+    let mut logged_peak_rss = None;
+    let local_key = match args.common.seed {
+        Some(seed) => generate_ed25519(seed),
+        None => Keypair::generate_ed25519(),
+    };
+    let (mut swarm, mut bandwidth) = start_swarm(
+        &args,
+        local_key,
+        tcp_config.clone(),
+        &write_stalls,
+        &transfers,
+        read_throttle.as_ref(),
+    )
+    .await?;
+
+    let relay_listen_addr = args
+        .common
+        .relay_multiaddr
+        .clone()
+        .with(Protocol::P2pCircuit);
+    if args.dry_run {
+        info!(
+            "Dry run: relay {} is reachable",
+            args.common.relay_multiaddr
+        );
+        info!(
+            "Dry run: would request a reservation on {} and serve {} and {}",
+            relay_listen_addr,
+            libp2p_perf::PROTOCOL_NAME,
+            args.common.signal_protocol()
+        );
+        if let Some(path) = &args.ticket_file {
+            info!("Dry run: would write ticket to {}", path.display());
+        }
+        if let Some(count) = args.stress_reservations {
+            info!(
+                "Dry run: would hold {} additional reservations on the relay",
+                count
+            );
+        }
+        return Ok(());
+    }
+    if args.reserve_only {
+        info!("Reserve only: holding the reservation without serving runs");
+    }
+    let admin_socket = match &args.admin_socket {
+        Some(path) => Some(AdminSocket::bind(path, &args.common.relay_multiaddr).await?),
+        None => None,
+    };
+    let mut relay_listener = swarm.listen_on(relay_listen_addr.clone()).unwrap();
+    let mut extra_relay_listeners = Vec::new();
+    for relay in &args.extra_relay {
+        extra_relay_listeners.push(swarm.listen_on(relay.clone().with(Protocol::P2pCircuit))?);
+    }
+    let mut reservation_requested_at = Instant::now();
+    if let Some(count) = args.stress_reservations {
+        warn!(
+            "Stress: holding {} additional reservations on {}",
+            count, args.common.relay_multiaddr
+        );
+        let reservations = stress::Reservations::new(count.get());
+        for index in 1..=count.get() {
+            let reservations = reservations.clone();
+            let relay_listen_addr = relay_listen_addr.clone();
+            let options = TransportOptions {
+                tcp_config: tcp_config.clone(),
+                tcp_quickack: args.tcp_quickack,
+                keepalive_interval: args.keepalive_interval,
+                insecure_plaintext: args.insecure_plaintext,
+                dial_timeout: args.dial_timeout,
+            };
+            async_std::task::spawn(async move {
+                if let Err(error) = reservations.hold(relay_listen_addr, options, index).await {
+                    warn!("Stress identity {}/{}: {}", index, count, error);
+                }
+            });
+        }
+    }
+    let mut backup_listener = None;
+    let mut relay_lost_at: Option<Instant> = None;
+    let mut stalls_at_last_perf_event = StallStats::default();
+    let mut reservation_accepted_at = None;
+    // Senders whose first circuit and first hole punch attempt were timed.
+    let mut circuit_senders = HashSet::new();
+    let mut hole_punch_senders = HashSet::new();
+    let mut backoff = Backoff::new(
+        args.rate_limit_backoff,
+        args.rate_limit_max_backoff,
+        args.rate_limit_retries,
+    );
+    let mut retry_reservation = futures::future::pending().boxed().fuse();
+
+    let mut window_closed = match args.available_window {
+        Some(window) => futures_timer::Delay::new(window).boxed(),
+        None => futures::future::pending().boxed(),
+    }
+    .fuse();
+    let mut accepting_runs = true;
+    // When each sender's plan was last accepted or it last disconnected,
+    // whichever is later. The perf server does not report individual runs.
+    let mut last_runs: HashMap<PeerId, Instant> = HashMap::new();
+    let mut results = args.results_dir.as_deref().map(ResultsDir::new);
+    let mut completed_transfers = transfers.subscribe();
 
-    swarm
-        .listen_on(args.relay_multiaddr.with(Protocol::P2pCircuit))
-        .unwrap();
+    // When each public address was last confirmed by the relay.
+    let mut confirmed_addrs: HashMap<Multiaddr, Instant> = swarm
+        .external_addresses()
+        .map(|address| (address.clone(), Instant::now()))
+        .collect();
+    let mut expire_addrs = match args.external_address_ttl {
+        Some(_) => futures_timer::Delay::new(args.identify_interval).boxed(),
+        None => futures::future::pending().boxed(),
+    }
+    .fuse();
+
+    let mut network_settled = futures::future::pending().boxed().fuse();
+    let mut reconnecting_relay = false;
+
+    let mut rotate_identity = match args.rotate_identity {
+        Some(interval) => futures_timer::Delay::new(interval).boxed(),
+        None => futures::future::pending().boxed(),
+    }
+    .fuse();
+    // Whether the identity is rotated once in-flight runs finished.
+    let mut rotation_due = false;
+    // The swarm of the next identity, set up while the current one keeps
+    // serving.
+    let mut next_identity = futures::future::pending().boxed_local().fuse();
+    // Swarm of the previous identity, kept and polled until the relay
+    // accepted the reservation of the new one and its in-flight runs
+    // finished, so the receiver stays reserved throughout.
+    let mut retiring_swarm: Option<Swarm<Behaviour>> = None;
+    let mut retiring_replaced = false;
 
     loop {
-        match swarm.next().await.unwrap() {
+        let event = futures::select! {
+            event = swarm.select_next_some() => event,
+            _ = poll_retiring_swarm(
+                &mut retiring_swarm,
+                retiring_replaced,
+                &relay_peer_ids,
+                &transfers,
+                read_throttle.as_ref(),
+            ).fuse() => continue,
+            (peer, transfer) = completed_transfers.select_next_some() => {
+                if let Some(results) = results.as_mut() {
+                    if let Some(path) = results.record(peer, transfer)? {
+                        info!("Wrote run record of {} to {}", peer, path.display());
+                    }
+                }
+                continue;
+            }
+            _ = window_closed => {
+                info!("Availability window elapsed, cancelling relay reservation");
+                swarm.remove_listener(relay_listener);
+                for listener in extra_relay_listeners.drain(..).chain(backup_listener.take()) {
+                    swarm.remove_listener(listener);
+                }
+                accepting_runs = false;
+                if let Some(admin_socket) = &admin_socket {
+                    admin_socket.lost(None);
+                }
+                if swarm.connected_peers().all(|peer_id| relay_peer_ids.contains(peer_id)) {
+                    info!("No runs in flight, exiting");
+                    return Ok(());
+                }
+                continue;
+            }
+            _ = expire_addrs => {
+                let ttl = args.external_address_ttl.expect("expiry requires a TTL");
+                confirmed_addrs.retain(|address, confirmed_at| {
+                    if confirmed_at.elapsed() < ttl {
+                        return true;
+                    }
+                    warn!(
+                        "Relay has not confirmed public address {} for {}, forgetting it",
+                        address,
+                        humantime::format_duration(ttl)
+                    );
+                    swarm.remove_external_address(address);
+                    false
+                });
+                expire_addrs = futures_timer::Delay::new(args.identify_interval).boxed().fuse();
+                continue;
+            }
+            _ = network_settled => {
+                network_settled = futures::future::pending().boxed().fuse();
+                if !accepting_runs {
+                    continue;
+                }
+                let stale_addrs: Vec<_> = confirmed_addrs.drain().map(|(address, _)| address).collect();
+                for address in &stale_addrs {
+                    swarm.remove_external_address(address);
+                }
+                info!(
+                    "Local addresses changed, forgetting public addresses {:?} and reconnecting to the relay",
+                    stale_addrs
+                );
+                swarm.remove_listener(relay_listener);
+                if swarm.disconnect_peer_id(relay_peer_id).is_ok() {
+                    // Listen again once the connection is closed so the
+                    // reservation is requested over a new connection.
+                    reconnecting_relay = true;
+                } else {
+                    relay_listener = swarm.listen_on(relay_listen_addr.clone())?;
+                    reservation_requested_at = Instant::now();
+                }
+                continue;
+            }
+            _ = rotate_identity => {
+                rotate_identity = futures::future::pending().boxed().fuse();
+                if !accepting_runs {
+                    continue;
+                }
+                if !swarm.connected_peers().all(|peer_id| relay_peer_ids.contains(peer_id)) {
+                    info!("Identity rotation due, waiting for in-flight runs to finish");
+                    rotation_due = true;
+                    continue;
+                }
+                rotation_due = false;
+                next_identity = start_swarm(
+                    &args,
+                    Keypair::generate_ed25519(),
+                    tcp_config.clone(),
+                    &write_stalls,
+                    &transfers,
+                    read_throttle.as_ref(),
+                )
+                .boxed_local()
+                .fuse();
+                continue;
+            }
+            result = next_identity => {
+                let interval = args.rotate_identity.expect("rotation requires an interval");
+                rotate_identity = futures_timer::Delay::new(interval).boxed().fuse();
+                let (new_swarm, new_bandwidth) = match result {
+                    Ok(started) => started,
+                    Err(error) => {
+                        warn!(
+                            "Failed to set up the next identity, keeping {}: {}",
+                            swarm.local_peer_id(),
+                            error
+                        );
+                        continue;
+                    }
+                };
+                info!(
+                    "Rotating identity from {} to {}",
+                    swarm.local_peer_id(),
+                    new_swarm.local_peer_id()
+                );
+                retiring_swarm = Some(std::mem::replace(&mut swarm, new_swarm));
+                retiring_replaced = false;
+                bandwidth = new_bandwidth;
+                relay_listener = swarm.listen_on(relay_listen_addr.clone())?;
+                extra_relay_listeners.clear();
+                for relay in &args.extra_relay {
+                    extra_relay_listeners.push(swarm.listen_on(relay.clone().with(Protocol::P2pCircuit))?);
+                }
+                reservation_requested_at = Instant::now();
+                reservation_accepted_at = None;
+                backup_listener = None;
+                relay_lost_at = None;
+                circuit_senders.clear();
+                hole_punch_senders.clear();
+                last_runs.clear();
+                confirmed_addrs = swarm
+                    .external_addresses()
+                    .map(|address| (address.clone(), Instant::now()))
+                    .collect();
+                continue;
+            }
+            _ = retry_reservation => {
+                if accepting_runs {
+                    info!("Retrying reservation request (retry {})", backoff.retries());
+                    relay_listener = swarm.listen_on(relay_listen_addr.clone())?;
+                    reservation_requested_at = Instant::now();
+                }
+                continue;
+            }
+        };
+
+        if let Some(audit_log) = audit_log.as_mut() {
+            audit_log.record_swarm_event(&event, &bandwidth)?;
+        }
+        if let Some(admin_socket) = &admin_socket {
+            record_reservation_status(
+                admin_socket,
+                &event,
+                &swarm,
+                relay_peer_id,
+                &relay_listen_addr,
+            );
+        }
+
+        match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 info!("Listening on {:?}", address);
+                if args.follow_network_changes && is_interface_addr(&address) {
+                    network_settled = futures_timer::Delay::new(NETWORK_SETTLE_DELAY)
+                        .boxed()
+                        .fuse();
+                }
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                info!("No longer listening on {:?}", address);
+                if args.follow_network_changes && is_interface_addr(&address) {
+                    network_settled = futures_timer::Delay::new(NETWORK_SETTLE_DELAY)
+                        .boxed()
+                        .fuse();
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id: peer_id,
+                    renewal,
+                    ..
+                },
+            )) if Some(peer_id) == backup_relay_peer_id => {
+                if renewal {
+                    info!("Backup relay {} renewed our reservation", peer_id);
+                } else {
+                    let lost_at = relay_lost_at.expect("backup reservations follow a lost relay");
+                    info!(
+                        "Backup relay {} accepted our reservation {:.4} s after the relay was lost",
+                        peer_id,
+                        lost_at.elapsed().as_secs_f64()
+                    );
+                    if let (Some(path), Some(backup_relay)) =
+                        (&args.ticket_file, &args.backup_relay)
+                    {
+                        let circuit_addr = backup_relay
+                            .clone()
+                            .with(Protocol::P2pCircuit)
+                            .with(Protocol::P2p(*swarm.local_peer_id()));
+                        write_ticket(path, &circuit_addr)?;
+                        info!("Wrote ticket {} to {}", circuit_addr, path.display());
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id: peer_id,
+                    renewal,
+                    ..
+                },
+            )) if peer_id != relay_peer_id => {
+                if renewal {
+                    info!("Extra relay {} renewed our reservation", peer_id);
+                } else {
+                    info!("Extra relay {} accepted our reservation request", peer_id);
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
-                relay::client::Event::ReservationReqAccepted { .. },
+                relay::client::Event::ReservationReqAccepted { renewal, .. },
             )) => {
-                info!("Relay accepted our reservation request");
+                backoff.reset();
+                if renewal {
+                    info!("Relay renewed our reservation");
+                } else {
+                    info!(
+                        "Relay accepted our reservation request in {:.4} s",
+                        reservation_requested_at.elapsed().as_secs_f64()
+                    );
+                    reservation_accepted_at = Some(Instant::now());
+                    if let Some(retiring) = &retiring_swarm {
+                        if retiring
+                            .connected_peers()
+                            .all(|peer_id| relay_peer_ids.contains(peer_id))
+                        {
+                            info!("Dropping previous identity {}", retiring.local_peer_id());
+                            retiring_swarm = None;
+                        } else {
+                            info!(
+                                "Keeping previous identity {} until its in-flight runs finish",
+                                retiring.local_peer_id()
+                            );
+                            retiring_replaced = true;
+                        }
+                    }
+                    if let Some(path) = &args.ticket_file {
+                        let circuit_addr = relay_listen_addr
+                            .clone()
+                            .with(Protocol::P2p(*swarm.local_peer_id()));
+                        write_ticket(path, &circuit_addr)?;
+                        info!("Wrote ticket {} to {}", circuit_addr, path.display());
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqFailed {
+                    relay_peer_id: peer_id,
+                    error,
+                    ..
+                },
+            )) if peer_id == relay_peer_id && accepting_runs && is_rate_limited(&error) => {
+                // The relay closes the listener on failure, so listen on the
+                // circuit address again once the backoff elapsed.
+                let delay = backoff.next_delay().ok_or_else(|| {
+                    format!(
+                        "rate limited by relay: reservation refused after {} retries",
+                        backoff.retries()
+                    )
+                })?;
+                warn!(
+                    "Rate limited by relay: reservation refused, retrying in {}",
+                    humantime::format_duration(delay)
+                );
+                retry_reservation = futures_timer::Delay::new(delay).boxed().fuse();
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::InboundCircuitEstablished { src_peer_id, .. },
+            )) => {
+                info!("Accepted STOP request for circuit from {}", src_peer_id);
+                if circuit_senders.insert(src_peer_id) {
+                    log_since_reservation("First circuit", src_peer_id, reservation_accepted_at);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request: signal::Request::Propose { plan, config },
+                        channel,
+                        ..
+                    },
+            })) => {
+                let local_config = signal::PeerConfig::local(
+                    args.common.transport,
+                    args.insecure_plaintext,
+                    args.tcp_quickack,
+                );
+                let mismatches = local_config.mismatches(&config);
+                if !mismatches.is_empty() {
+                    warn!(
+                        "Configuration mismatch with {} ({}): sender {}, receiver {}",
+                        peer,
+                        mismatches.join(", "),
+                        config,
+                        local_config
+                    );
+                }
+                let too_soon = args.min_run_interval.and_then(|interval| {
+                    let elapsed = last_runs.get(&peer)?.elapsed();
+                    let remaining = interval.checked_sub(elapsed)?;
+                    Some(format!(
+                        "runs from the same sender must be at least {} apart, retry in {}",
+                        humantime::format_duration(interval),
+                        humantime::format_duration(Duration::from_secs(
+                            remaining.as_secs_f64().ceil() as u64
+                        ))
+                    ))
+                });
+                let overloaded = overload(&args, &swarm, &relay_peer_ids, peer)?;
+                if let Some(reason) = &overloaded {
+                    warn!("Alarm: {}, refusing new runs", reason);
+                    if let Some(audit_log) = audit_log.as_mut() {
+                        audit_log.record_alarm(reason)?;
+                    }
+                }
+                // Senders may still reach the receiver over a direct
+                // connection once the reservation was cancelled.
+                let unavailable = if args.reserve_only {
+                    Some("receiver only holds its relay reservation (--reserve-only)".to_string())
+                } else {
+                    (!accepting_runs).then(|| "availability window closed".to_string())
+                };
+                let response = match unavailable
+                    .map_or(Ok(()), Err)
+                    .and_then(|()| {
+                        Capabilities::local(args.max_payload)
+                            .check_compatible(plan.bench, plan.payload)
+                    })
+                    .and_then(|()| too_soon.map_or(Ok(()), Err))
+                    .and_then(|()| {
+                        overloaded.map_or(Ok(()), |reason| {
+                            Err(format!("receiver overloaded: {reason}"))
+                        })
+                    }) {
+                    Ok(()) => {
+                        info!("Accepted run plan from {}: {:?}", peer, plan);
+                        last_runs.insert(peer, Instant::now());
+                        let now = SystemTime::now();
+                        if let Some(results) = results.as_mut() {
+                            results.start(peer, plan.clone(), now);
+                        }
+                        signal::Response::Accepted {
+                            plan,
+                            config: local_config,
+                            unix_time_ms: signal::unix_time_ms(now),
+                        }
+                    }
+                    Err(reason) => {
+                        warn!("Rejected run plan from {}: {}", peer, reason);
+                        signal::Response::Rejected { reason }
+                    }
+                };
+                if swarm
+                    .behaviour_mut()
+                    .signal
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Failed to answer run plan from {}: connection closed", peer);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request:
+                            signal::Request::Report {
+                                upload_bytes,
+                                download_bytes,
+                            },
+                        channel,
+                        ..
+                    },
+            })) => {
+                let transfer = take_transfer(
+                    &transfers,
+                    read_throttle.as_ref(),
+                    &peer,
+                    upload_bytes,
+                    download_bytes,
+                );
+                if swarm
+                    .behaviour_mut()
+                    .signal
+                    .send_response(channel, signal::Response::Report { transfer })
+                    .is_err()
+                {
+                    warn!("Failed to report transfer to {}: connection closed", peer);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(event)) => {
+                info!("{:?}", event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Perf(())) => {
+                // The perf server reports each request and each sent
+                // response, i.e., download, without telling them apart.
+                let stalls = write_stalls.snapshot();
+                if stalls.stalls > stalls_at_last_perf_event.stalls {
+                    info!(
+                        "Writes stalled while serving perf requests: {}",
+                        stalls.since(stalls_at_last_perf_event)
+                    );
+                }
+                stalls_at_last_perf_event = stalls;
+                let peak_rss = read_throttle.as_ref().and_then(ReadThrottle::peak_rss);
+                if peak_rss > logged_peak_rss {
+                    info!(
+                        "Peak resident set size while reads were throttled: {}",
+                        format_bytes(peak_rss.unwrap_or_default() as usize)
+                    );
+                    logged_peak_rss = peak_rss;
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => {
                 info!("{:?}", event)
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                event @ dcutr::Event::InitiatedDirectConnectionUpgrade { remote_peer_id, .. },
+            )) => {
+                info!("{:?}", event);
+                if hole_punch_senders.insert(remote_peer_id) {
+                    log_since_reservation(
+                        "First hole punch attempt",
+                        remote_peer_id,
+                        reservation_accepted_at,
+                    );
+                }
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => {
                 info!("{:?}", event)
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info: identify::Info { observed_addr, .. },
+            })) if peer_id == relay_peer_id => {
+                if !args.addr_filter().allows(&observed_addr) {
+                    continue;
+                }
+                if !confirmed_addrs.contains_key(&observed_addr) {
+                    info!("Relay says our public address is now {}", observed_addr);
+                    swarm.add_external_address(observed_addr.clone());
+                }
+                confirmed_addrs.insert(observed_addr, Instant::now());
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
                 info!("{:?}", event)
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Err(error),
+                ..
+            })) if peer == relay_peer_id && args.backup_relay.is_some() => {
+                warn!("Ping to the relay failed: {}", error);
+                let _ = swarm.disconnect_peer_id(relay_peer_id);
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
             SwarmEvent::ConnectionEstablished {
                 peer_id, endpoint, ..
             } => {
                 info!("Established connection to {} via {:?}", peer_id, endpoint);
             }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } if peer_id == relay_peer_id && reconnecting_relay => {
+                reconnecting_relay = false;
+                if accepting_runs {
+                    relay_listener = swarm.listen_on(relay_listen_addr.clone())?;
+                    reservation_requested_at = Instant::now();
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } if peer_id == relay_peer_id
+                && accepting_runs
+                && reservation_accepted_at.is_some()
+                && backup_listener.is_none() =>
+            {
+                if let Some(backup_relay) = &args.backup_relay {
+                    warn!(
+                        "Lost connection to the relay, requesting a reservation on backup relay {}",
+                        backup_relay
+                    );
+                    relay_lost_at = Some(Instant::now());
+                    backup_listener =
+                        Some(swarm.listen_on(backup_relay.clone().with(Protocol::P2pCircuit))?);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } if !accepting_runs
+                && swarm
+                    .connected_peers()
+                    .all(|peer_id| relay_peer_ids.contains(peer_id)) =>
+            {
+                info!("Last in-flight run from {} finished, exiting", peer_id);
+                return Ok(());
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } if !relay_peer_ids.contains(&peer_id) => {
+                last_runs.insert(peer_id, Instant::now());
+                if rotation_due
+                    && swarm
+                        .connected_peers()
+                        .all(|peer_id| relay_peer_ids.contains(peer_id))
+                {
+                    // Rotate on the next iteration of the event loop.
+                    rotate_identity = futures::future::ready(()).boxed().fuse();
+                }
+            }
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 info!("Outgoing connection error to {:?}: {}", peer_id, error);
             }
@@ -103,83 +1065,322 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 
+/// Handle the next event of the swarm of the previous identity during an
+/// identity rotation, pending if there is none. The previous identity keeps
+/// its reservation, accepts circuits, and serves perf and report requests
+/// until the new identity is reserved. It refuses new run plans, so senders
+/// move to the new identity. Once `replaced` by a reserved new identity, it
+/// is dropped as soon as only relays remain connected.
+async fn poll_retiring_swarm(
+    retiring_swarm: &mut Option<Swarm<Behaviour>>,
+    replaced: bool,
+    relay_peer_ids: &[PeerId],
+    transfers: &TransferTimings,
+    read_throttle: Option<&ReadThrottle>,
+) {
+    let Some(swarm) = retiring_swarm.as_mut() else {
+        return futures::future::pending().await;
+    };
+    let event = swarm.select_next_some().await;
+    let local_peer_id = *swarm.local_peer_id();
+    match event {
+        SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request: signal::Request::Propose { .. },
+                    channel,
+                    ..
+                },
+        })) => {
+            warn!(
+                "Previous identity {} rejected run plan from {}: rotating identity",
+                local_peer_id, peer
+            );
+            let response = signal::Response::Rejected {
+                reason: "receiver is rotating its identity, re-read its ticket".to_string(),
+            };
+            let _ = swarm
+                .behaviour_mut()
+                .signal
+                .send_response(channel, response);
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+            peer,
+            message:
+                request_response::Message::Request {
+                    request:
+                        signal::Request::Report {
+                            upload_bytes,
+                            download_bytes,
+                        },
+                    channel,
+                    ..
+                },
+        })) => {
+            let transfer = take_transfer(
+                transfers,
+                read_throttle,
+                &peer,
+                upload_bytes,
+                download_bytes,
+            );
+            let response = signal::Response::Report { transfer };
+            let _ = swarm
+                .behaviour_mut()
+                .signal
+                .send_response(channel, response);
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+            relay::client::Event::ReservationReqAccepted { renewal: true, .. },
+        )) => info!("Relay renewed the reservation of previous identity {local_peer_id}"),
+        SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+            relay::client::Event::InboundCircuitEstablished { src_peer_id, .. },
+        )) => info!(
+            "Previous identity {} accepted STOP request for circuit from {}",
+            local_peer_id, src_peer_id
+        ),
+        event => debug!("Previous identity {}: {:?}", local_peer_id, event),
+    }
+    if replaced
+        && swarm
+            .connected_peers()
+            .all(|peer_id| relay_peer_ids.contains(peer_id))
+    {
+        info!("Dropping previous identity {}", local_peer_id);
+        *retiring_swarm = None;
+    }
+}
+
+/// Whether `address` is a non-loopback address of a local interface rather
+/// than a relayed address.
+fn is_interface_addr(address: &Multiaddr) -> bool {
+    !is_loopback(address) && !address.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// Log how long after the relay accepted our reservation `what` happened for
+/// `sender`. Long gaps point at slow discovery on the sender's side rather
+/// than queuing at the relay.
+fn log_since_reservation(what: &str, sender: PeerId, reservation_accepted_at: Option<Instant>) {
+    match reservation_accepted_at {
+        Some(accepted_at) => info!(
+            "{} with {} {:.4} s after the relay accepted our reservation",
+            what,
+            sender,
+            accepted_at.elapsed().as_secs_f64()
+        ),
+        None => info!(
+            "{} with {} before the relay accepted our reservation",
+            what, sender
+        ),
+    }
+}
+
+/// Take the latest transfer with `peer` of at least the given sizes, with the
+/// peak resident set size under --read-rate.
+fn take_transfer(
+    transfers: &TransferTimings,
+    read_throttle: Option<&ReadThrottle>,
+    peer: &PeerId,
+    upload_bytes: usize,
+    download_bytes: usize,
+) -> Option<Transfer> {
+    let transfer = transfers.take(peer, upload_bytes, download_bytes)?;
+    Some(Transfer {
+        peak_rss: read_throttle.and_then(ReadThrottle::peak_rss),
+        ..transfer
+    })
+}
+
+/// Describe the first resource threshold the receiver exceeds, if any, when
+/// `sender` proposes a run.
+fn overload(
+    args: &Args,
+    swarm: &Swarm<Behaviour>,
+    relay_peer_ids: &[PeerId],
+    sender: PeerId,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(max_rss) = args.max_rss {
+        let rss = resident_set_size()? as usize;
+        if rss > max_rss {
+            return Ok(Some(format!(
+                "resident set size of {} exceeds --max-rss of {}",
+                format_bytes(rss),
+                format_bytes(max_rss)
+            )));
+        }
+    }
+    if let Some(max_fds) = args.max_fds {
+        let fds = open_fds()?;
+        if fds > max_fds {
+            return Ok(Some(format!(
+                "{fds} open file descriptors exceed --max-fds of {max_fds}"
+            )));
+        }
+    }
+    if let Some(max_runs) = args.max_concurrent_runs {
+        let runs = swarm
+            .connected_peers()
+            .filter(|peer_id| **peer_id != sender && !relay_peer_ids.contains(peer_id))
+            .count();
+        if runs >= max_runs {
+            return Ok(Some(format!(
+                "{runs} concurrent runs reach --max-concurrent-runs of {max_runs}"
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Track the reservation on `relay_peer_id` at `relay_listen_addr` for the
+/// admin socket.
+fn record_reservation_status<E>(
+    admin_socket: &AdminSocket,
+    event: &SwarmEvent<BehaviourEvent, E>,
+    swarm: &Swarm<Behaviour>,
+    relay_peer_id: PeerId,
+    relay_listen_addr: &Multiaddr,
+) {
+    match event {
+        SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+            relay::client::Event::ReservationReqAccepted {
+                relay_peer_id: peer_id,
+                renewal,
+                ..
+            },
+        )) if *peer_id == relay_peer_id => admin_socket.accepted(
+            relay_listen_addr
+                .clone()
+                .with(Protocol::P2p(*swarm.local_peer_id())),
+            *renewal,
+        ),
+        SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+            relay::client::Event::ReservationReqFailed {
+                relay_peer_id: peer_id,
+                error,
+                ..
+            },
+        )) if *peer_id == relay_peer_id => admin_socket.lost(Some(if is_rate_limited(error) {
+            "rate limited by relay".to_string()
+        } else {
+            format!("reservation refused: {error:?}")
+        })),
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } if *peer_id == relay_peer_id => {
+            admin_socket.lost(Some("connection to the relay closed".to_string()))
+        }
+        _ => {}
+    }
+}
+
+/// Build a swarm with the identity `local_key`, listen, and learn our public
+/// address from the relay.
+async fn start_swarm(
+    args: &Args,
+    local_key: Keypair,
+    tcp_config: tcp::Config,
+    write_stalls: &WriteStalls,
+    transfers: &TransferTimings,
+    read_throttle: Option<&ReadThrottle>,
+) -> Result<(Swarm<Behaviour>, Arc<BandwidthSinks>), Box<dyn Error>> {
+    let (mut swarm, bandwidth) = build_swarm(
+        args,
+        local_key,
+        tcp_config,
+        write_stalls,
+        transfers,
+        read_throttle,
+    )
+    .await?;
+    swarm_listen(
+        &mut swarm,
+        args.common.transport,
+        args.listen_wait,
+        args.event_handling,
+    )
+    .await?;
+    learn_external_address(
+        &mut swarm,
+        args.common.relay_multiaddr.clone(),
+        args.observer.clone(),
+        args.address_observations,
+        &args.addr_filter(),
+        args.event_handling,
+    )
+    .await?;
+    Ok((swarm, bandwidth))
+}
+
 async fn build_swarm(
-    seed: u8,
+    args: &Args,
+    local_key: Keypair,
     tcp_config: tcp::Config,
-) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
-    let local_key = generate_ed25519(seed);
+    write_stalls: &WriteStalls,
+    transfers: &TransferTimings,
+    read_throttle: Option<&ReadThrottle>,
+) -> Result<(Swarm<Behaviour>, Arc<BandwidthSinks>), Box<dyn Error>> {
     let local_peer_id = PeerId::from(local_key.public());
+    info!("Local peer id: {}", local_peer_id);
 
     let (relay_transport, client) = relay::client::new(local_peer_id);
 
-    let transport = {
-        let relay_tcp_quic_transport = relay_transport
-            .or_transport(tcp::async_io::Transport::new(tcp_config))
-            .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&local_key).unwrap())
-            .multiplex(yamux::Config::default())
-            .or_transport(quic::async_std::Transport::new(quic::Config::new(
-                &local_key,
-            )));
-
-        dns::DnsConfig::system(relay_tcp_quic_transport)
-            .await
-            .unwrap()
-            .map(|either_output, _| match either_output {
-                Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
-                Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+    let write_stalls = write_stalls.clone();
+    let transfers = transfers.clone();
+    let read_throttle = read_throttle.cloned();
+    let transport = build_transport_with(
+        &local_key,
+        relay_transport,
+        TransportOptions {
+            tcp_config,
+            tcp_quickack: args.tcp_quickack,
+            keepalive_interval: args.keepalive_interval,
+            insecure_plaintext: args.insecure_plaintext,
+            dial_timeout: args.dial_timeout,
+        },
+        |transport| {
+            Transport::map(transport, move |(peer_id, muxer), _| {
+                let muxer = match &read_throttle {
+                    Some(read_throttle) => read_throttle.instrument(muxer),
+                    None => muxer,
+                };
+                let muxer = write_stalls.instrument(muxer);
+                (peer_id, transfers.instrument(peer_id, muxer))
             })
             .boxed()
-    };
+        },
+    )
+    .await?;
+    let (transport, bandwidth) = transport.with_bandwidth_logging();
 
+    // A killed relay is otherwise only noticed once the connection idles out.
+    let ping_config = match (&args.backup_relay, args.keepalive_interval) {
+        (Some(_), _) => ping::Config::new()
+            .with_interval(RELAY_PING_INTERVAL)
+            .with_timeout(RELAY_PING_INTERVAL * 2),
+        (None, Some(interval)) => ping::Config::new().with_interval(interval),
+        (None, None) => ping::Config::new(),
+    };
     let behaviour = Behaviour {
         relay_client: client,
-        ping: ping::Behaviour::new(ping::Config::new()),
-        identify: identify::Behaviour::new(identify::Config::new(
-            "/TODO/0.0.1".to_string(),
-            local_key.public(),
-        )),
+        ping: ping::Behaviour::new(ping_config),
+        identify: identify::Behaviour::new(
+            identify::Config::new("/TODO/0.0.1".to_string(), local_key.public())
+                .with_agent_version(Capabilities::local(args.max_payload).to_agent_version())
+                .with_interval(args.identify_interval),
+        ),
         dcutr: dcutr::Behaviour::new(local_peer_id),
-        perf: Default::default(),
+        perf: (!args.reserve_only).then(Default::default).into(),
+        signal: signal::new_behaviour(args.common.signal_protocol()),
     };
 
-    Ok(SwarmBuilder::with_async_std_executor(transport, behaviour, local_peer_id).build())
-}
-
-async fn learn_external_address(
-    swarm: &mut Swarm<Behaviour>,
-    relay_address: Multiaddr,
-) -> Result<(), Box<dyn Error>> {
-    // Connect to the relay server. Not for the reservation or relayed
-    // connection, but to (a) learn our local public address and (b) enable
-    // a freshly started relay to learn its public address.
-    swarm.dial(relay_address.clone())?;
-    let mut learned_observed_addr = false;
-    let mut told_relay_observed_addr = false;
-
-    loop {
-        match swarm.next().await.unwrap() {
-            SwarmEvent::NewListenAddr { .. } => {}
-            SwarmEvent::Dialing { .. } => {}
-            SwarmEvent::ConnectionEstablished { .. } => {}
-            SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent { .. })) => {
-                info!("Notified relay of its public address");
-                told_relay_observed_addr = true;
-            }
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
-                info: identify::Info { observed_addr, .. },
-                ..
-            })) => {
-                info!("Relay says our public address is {}", observed_addr);
-                swarm.add_external_address(observed_addr);
-                learned_observed_addr = true;
-            }
-            event => panic!("{event:?}"),
-        }
-
-        if learned_observed_addr && told_relay_observed_addr {
-            return Ok(());
-        }
+    let mut config = swarm::Config::with_async_std_executor()
+        .with_dial_concurrency_factor(args.dial_concurrency_factor);
+    if let Some(interval) = args.keepalive_interval {
+        config = config.with_idle_connection_timeout(interval * KEEPALIVE_IDLE_INTERVALS);
     }
+    let swarm = Swarm::new(transport, behaviour, local_peer_id, config);
+    Ok((swarm, bandwidth))
 }
@@ -0,0 +1,111 @@
+//! Per-run records written by the receiver to `<dir>/<peer_id>/<run_id>.json`,
+//! so results are kept where senders are throwaway devices that cannot
+//! persist their own. Each record is the receiver's timing of one throughput
+//! run, as timed by [`crate::transfers`], together with the sender and run
+//! identity from the run plan it agreed to over `/bench/signal/1`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use libp2p::PeerId;
+use serde::Serialize;
+
+use crate::signal::{self, RunPlan};
+use crate::transfers::Transfer;
+use crate::BenchMode;
+
+/// Runs of the plan a sender's runs are recorded under.
+struct Session {
+    plan: RunPlan,
+    accepted_at_ms: u64,
+    recorded_runs: usize,
+}
+
+/// The record of one run.
+#[derive(Serialize)]
+struct RunRecord<'a> {
+    sender: String,
+    /// `<plan accepted at, in ms since the Unix epoch>-<run>`.
+    run_id: String,
+    /// Number of the run within the plan, from 1, warmup runs included.
+    run: usize,
+    plan: &'a RunPlan,
+    finished_at_ms: u64,
+    transfer: Transfer,
+}
+
+/// Directory the receiver writes run records to.
+pub struct ResultsDir {
+    root: PathBuf,
+    sessions: HashMap<PeerId, Session>,
+}
+
+impl ResultsDir {
+    pub fn new(root: &Path) -> Self {
+        ResultsDir {
+            root: root.to_path_buf(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Record the runs of `plan`, accepted from `sender` at `accepted_at`,
+    /// replacing any earlier plan of the sender. Only throughput runs are
+    /// recorded, as other modes do not transfer one payload per run.
+    pub fn start(&mut self, sender: PeerId, plan: RunPlan, accepted_at: SystemTime) {
+        if plan.bench != BenchMode::Throughput {
+            self.sessions.remove(&sender);
+            return;
+        }
+        self.sessions.insert(
+            sender,
+            Session {
+                plan,
+                accepted_at_ms: signal::unix_time_ms(accepted_at),
+                recorded_runs: 0,
+            },
+        );
+    }
+
+    /// Write the record of `transfer` if it is the next run of the plan
+    /// accepted from `sender`, returning the path written. Transfers
+    /// smaller than the plan's payloads, e.g., of signaling, are skipped.
+    pub fn record(
+        &mut self,
+        sender: PeerId,
+        transfer: Transfer,
+    ) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let Some(session) = self.sessions.get_mut(&sender) else {
+            return Ok(None);
+        };
+        let plan = &session.plan;
+        if transfer.read_bytes < plan.upload_bytes || transfer.written_bytes < plan.download_bytes {
+            return Ok(None);
+        }
+        session.recorded_runs += 1;
+        let run = session.recorded_runs;
+        let run_id = format!("{}-{}", session.accepted_at_ms, run);
+        let record = RunRecord {
+            sender: sender.to_string(),
+            run_id: run_id.clone(),
+            run,
+            plan,
+            finished_at_ms: signal::unix_time_ms(SystemTime::now()),
+            transfer,
+        };
+
+        let dir = self.root.join(sender.to_string());
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{run_id}.json"));
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&record)?)?;
+        std::fs::rename(&tmp, &path)?;
+
+        if run >= plan.runs {
+            self.sessions.remove(&sender);
+        }
+        Ok(Some(path))
+    }
+}
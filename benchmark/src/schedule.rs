@@ -0,0 +1,259 @@
+//! Cron-like schedules for recurring sender sessions, so a sender can run as
+//! a long-term monitor of the path between two sites. Schedules have the
+//! five fields of cron, `minute hour day-of-month month day-of-week`, each
+//! `*`, a value, a range `a-b`, optionally stepped as in `*/6` or `0-30/10`,
+//! or a comma-separated list of these. Times are in UTC.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+/// Longest time searched for the next firing, long enough to include a
+/// leap day.
+const HORIZON: u64 = 5 * 366 * DAY;
+
+/// Values of one field, as a bit set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Field {
+    values: u64,
+    /// Whether the field is restricted, i.e., not `*`, which decides how
+    /// day-of-month and day-of-week combine.
+    restricted: bool,
+}
+
+impl Field {
+    fn parse(s: &str, name: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut values = 0;
+        for part in s.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => (range, step),
+                    _ => return Err(format!("invalid step '{step}' in {name} field '{s}'")),
+                },
+                None => (part, 1),
+            };
+            let parse = |value: &str| match value.parse::<u32>() {
+                Ok(value) if (min..=max).contains(&value) => Ok(value),
+                _ => Err(format!(
+                    "invalid {name} '{value}' in '{s}': expected {min} to {max}"
+                )),
+            };
+            let (start, end) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                // A stepped value runs to the end, as in `5/15`.
+                None if part.contains('/') => (parse(range)?, max),
+                None => (parse(range)?, parse(range)?),
+            };
+            if start > end {
+                return Err(format!("invalid range '{range}' in {name} field '{s}'"));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                values |= 1 << value;
+            }
+        }
+        Ok(Field {
+            values,
+            restricted: s != "*",
+        })
+    }
+
+    fn contains(&self, value: u64) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+/// A schedule parsed from a cron-like expression, such as `0 */6 * * *` for
+/// every six hours on the hour.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    expression: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl Schedule {
+    /// Return the first time after `after`, at the start of a minute, the
+    /// schedule fires at, or `None` if it fires at none within five years,
+    /// e.g., on February 30th.
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let start = after.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let mut t = (start / MINUTE + 1) * MINUTE;
+        while t < start + HORIZON {
+            let days = t / DAY;
+            let (_, month, day) = civil_from_days(days);
+            // 1970-01-01 was a Thursday.
+            let weekday = (days + 4) % 7;
+            let day_matches = match (self.days.restricted, self.weekdays.restricted) {
+                (true, true) => self.days.contains(day) || self.weekdays.contains(weekday),
+                _ => self.days.contains(day) && self.weekdays.contains(weekday),
+            };
+            if !self.months.contains(month) || !day_matches {
+                t = (days + 1) * DAY;
+            } else if !self.hours.contains(t % DAY / HOUR) {
+                t = (t / HOUR + 1) * HOUR;
+            } else if !self.minutes.contains(t % HOUR / MINUTE) {
+                t += MINUTE;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(t));
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+/// Parse a cron-like schedule such as `0 */6 * * *`.
+pub fn parse_schedule(s: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let [minutes, hours, days, months, weekdays] = fields[..] else {
+        return Err(format!(
+            "invalid schedule '{s}': expected 5 fields, minute hour day-of-month month \
+             day-of-week"
+        ));
+    };
+    let mut weekdays = Field::parse(weekdays, "day of week", 0, 7)?;
+    // Both 0 and 7 are Sunday.
+    if weekdays.contains(7) {
+        weekdays.values |= 1;
+    }
+    Ok(Schedule {
+        expression: fields.join(" "),
+        minutes: Field::parse(minutes, "minute", 0, 59)?,
+        hours: Field::parse(hours, "hour", 0, 23)?,
+        days: Field::parse(days, "day of month", 1, 31)?,
+        months: Field::parse(months, "month", 1, 12)?,
+        weekdays,
+    })
+}
+
+/// Convert days since the Unix epoch to a year, month and day, after
+/// Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Seconds since the Unix epoch of a UTC date and time.
+    fn at(year: u64, month: u64, day: u64, hour: u64, minute: u64) -> SystemTime {
+        let days = (0..)
+            .find(|days| civil_from_days(*days) == (year, month, day))
+            .unwrap();
+        UNIX_EPOCH + Duration::from_secs(days * DAY + hour * HOUR + minute * MINUTE)
+    }
+
+    fn next(schedule: &str, after: SystemTime) -> Option<SystemTime> {
+        parse_schedule(schedule).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(20_741), (2026, 10, 15));
+    }
+
+    #[test]
+    fn every_six_hours() {
+        let schedule = "0 */6 * * *";
+        assert_eq!(
+            next(schedule, at(2026, 10, 15, 13, 7)),
+            Some(at(2026, 10, 15, 18, 0))
+        );
+        assert_eq!(
+            next(schedule, at(2026, 10, 15, 18, 0)),
+            Some(at(2026, 10, 16, 0, 0))
+        );
+        assert_eq!(
+            next(schedule, at(2026, 12, 31, 23, 59)),
+            Some(at(2027, 1, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn fires_strictly_after() {
+        let after = at(2026, 10, 15, 13, 7) + Duration::from_secs(30);
+        assert_eq!(next("* * * * *", after), Some(at(2026, 10, 15, 13, 8)));
+    }
+
+    #[test]
+    fn lists_ranges_and_steps() {
+        let schedule = "5,20-30/5 9-17 * * 1-5";
+        // Thursday.
+        assert_eq!(
+            next(schedule, at(2026, 10, 15, 9, 6)),
+            Some(at(2026, 10, 15, 9, 20))
+        );
+        assert_eq!(
+            next(schedule, at(2026, 10, 15, 9, 30)),
+            Some(at(2026, 10, 15, 10, 5))
+        );
+        // Friday evening to Monday morning.
+        assert_eq!(
+            next(schedule, at(2026, 10, 16, 17, 30)),
+            Some(at(2026, 10, 19, 9, 5))
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // The 1st of the month or a Sunday, where 7 is Sunday as well.
+        let schedule = "0 0 1 * 7";
+        assert_eq!(
+            next(schedule, at(2026, 10, 15, 0, 0)),
+            Some(at(2026, 10, 18, 0, 0))
+        );
+        assert_eq!(
+            next(schedule, at(2026, 10, 25, 0, 0)),
+            Some(at(2026, 11, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn leap_day_and_never() {
+        assert_eq!(
+            next("30 12 29 2 *", at(2026, 10, 15, 0, 0)),
+            Some(at(2028, 2, 29, 12, 30))
+        );
+        assert_eq!(next("0 0 30 2 *", at(2026, 10, 15, 0, 0)), None);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        assert!(parse_schedule("0 */6 * *").is_err());
+        assert!(parse_schedule("0 */6 * * * *").is_err());
+        assert!(parse_schedule("60 * * * *").is_err());
+        assert!(parse_schedule("* 24 * * *").is_err());
+        assert!(parse_schedule("* * 0 * *").is_err());
+        assert!(parse_schedule("* * * 13 *").is_err());
+        assert!(parse_schedule("* * * * 8").is_err());
+        assert!(parse_schedule("*/0 * * * *").is_err());
+        assert!(parse_schedule("30-10 * * * *").is_err());
+        assert!(parse_schedule("mon * * * *").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::error::Error;
+use std::num::NonZeroUsize;
+
+use futures::StreamExt;
+use libp2p::{
+    core::multiaddr::{Multiaddr, Protocol},
+    identity::Keypair,
+    relay,
+    swarm::{self, NetworkBehaviour, Swarm, SwarmEvent},
+    tcp, PeerId,
+};
+use libp2p_perf::{RunDuration, RunParams};
+use log::{debug, info};
+
+use crate::{
+    build_transport, format_bytes, format_throughput, parse_size, stats::Histogram, ThroughputUnit,
+    TransportMethod, TransportOptions,
+};
+
+/// Behaviours of the selftest's receiver and sender, reported by
+/// `--version --json`.
+pub const BEHAVIOURS: &[&str] = &["relay-client", "perf-server", "perf-client"];
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    // Transport method (tcp or quic-v1).
+    #[arg(short, long, value_enum, default_value_t=TransportMethod::Tcp)]
+    transport: TransportMethod,
+
+    // Bytes sent and received per run (e.g., 100MB).
+    #[arg(long, value_parser = parse_size, default_value = "100MB")]
+    payload: usize,
+
+    // Number of runs.
+    #[arg(long, default_value = "3")]
+    runs: NonZeroUsize,
+
+    // Use plaintext instead of noise for TCP connections, to isolate the
+    // cost of the security layer.
+    #[arg(long)]
+    insecure_plaintext: bool,
+
+    // Keep TCP_QUICKACK set on TCP connections, i.e., acknowledge every
+    // segment right away instead of delaying ACKs (Linux only). Combine with
+    // --transport tcp-no-delay to disable Nagle's algorithm as well.
+    #[arg(long)]
+    tcp_quickack: bool,
+
+    // Unit of throughput in run records.
+    #[arg(long, value_enum, default_value_t = ThroughputUnit::Auto)]
+    units: ThroughputUnit,
+}
+
+#[derive(NetworkBehaviour)]
+struct ReceiverBehaviour {
+    relay_client: relay::client::Behaviour,
+    perf: libp2p_perf::server::Behaviour,
+}
+
+#[derive(NetworkBehaviour)]
+struct SenderBehaviour {
+    relay_client: relay::client::Behaviour,
+    perf: libp2p_perf::client::Behaviour,
+}
+
+/// Run the selftest until all runs completed.
+pub fn main(args: Args) -> Result<(), Box<dyn Error>> {
+    async_std::task::block_on(selftest(args))
+}
+
+async fn selftest(args: Args) -> Result<(), Box<dyn Error>> {
+    if args.insecure_plaintext && matches!(args.transport, TransportMethod::QuicV1) {
+        return Err("--insecure-plaintext is not supported with QUIC".into());
+    }
+    if args.tcp_quickack && matches!(args.transport, TransportMethod::QuicV1) {
+        return Err("--tcp-quickack is not supported with QUIC".into());
+    }
+    info!("DCUTR Bandwidth Benchmark: Loopback Selftest");
+    info!("Transport method: {:?}", args.transport);
+
+    let mut receiver = build_swarm(&args, |client| ReceiverBehaviour {
+        relay_client: client,
+        perf: Default::default(),
+    })
+    .await?;
+    let mut sender = build_swarm(&args, |client| SenderBehaviour {
+        relay_client: client,
+        perf: Default::default(),
+    })
+    .await?;
+
+    let listen_address: Multiaddr = match args.transport {
+        TransportMethod::Tcp | TransportMethod::TcpNoDelay => "/ip4/127.0.0.1/tcp/0".parse()?,
+        TransportMethod::QuicV1 => "/ip4/127.0.0.1/udp/0/quic-v1".parse()?,
+    };
+    receiver.listen_on(listen_address)?;
+    let receiver_address = loop {
+        match receiver.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => break address,
+            event => info!("{:?}", event),
+        }
+    };
+    let receiver_peer_id = *receiver.local_peer_id();
+    info!("Receiver listening on {}", receiver_address);
+    sender.dial(receiver_address.with(Protocol::P2p(receiver_peer_id)))?;
+
+    let params = RunParams {
+        to_send: args.payload,
+        to_receive: args.payload,
+    };
+    let mut completed_runs = 0;
+    // Round trips of whole runs, i.e., request latencies with small payloads.
+    let mut latencies = Histogram::default();
+    loop {
+        let event = futures::select! {
+            event = receiver.select_next_some() => {
+                debug!("Receiver: {:?}", event);
+                continue;
+            }
+            event = sender.select_next_some() => event,
+        };
+        match event {
+            SwarmEvent::ConnectionEstablished { endpoint, .. } => {
+                info!("Established connection via {:?}", endpoint);
+                sender.behaviour_mut().perf.perf(receiver_peer_id, params)?;
+            }
+            SwarmEvent::Behaviour(SenderBehaviourEvent::Perf(libp2p_perf::client::Event {
+                result,
+                ..
+            })) => {
+                let duration = result.map_err(|e| format!("Benchmark run failed: {e:?}"))?;
+                completed_runs += 1;
+                latencies.record(duration.upload + duration.download);
+                info!(
+                    "Loopback baseline run {}/{}: {}",
+                    completed_runs,
+                    args.runs.get(),
+                    format_run(params, duration, args.units)
+                );
+                if completed_runs == args.runs.get() {
+                    info!("Loopback run latency: {}", latencies);
+                    return Ok(());
+                }
+                sender.behaviour_mut().perf.perf(receiver_peer_id, params)?;
+            }
+            SwarmEvent::OutgoingConnectionError { error, .. } => {
+                return Err(format!("failed to dial the receiver: {error}").into());
+            }
+            event => debug!("Sender: {:?}", event),
+        }
+    }
+}
+
+async fn build_swarm<B: NetworkBehaviour>(
+    args: &Args,
+    behaviour: impl FnOnce(relay::client::Behaviour) -> B,
+) -> Result<Swarm<B>, Box<dyn Error>> {
+    let local_key = Keypair::generate_ed25519();
+    let local_peer_id = PeerId::from(local_key.public());
+    let (relay_transport, client) = relay::client::new(local_peer_id);
+
+    let tcp_config = tcp::Config::default().nodelay(args.transport == TransportMethod::TcpNoDelay);
+    let transport = build_transport(
+        &local_key,
+        relay_transport,
+        TransportOptions {
+            tcp_config,
+            tcp_quickack: args.tcp_quickack,
+            keepalive_interval: None,
+            insecure_plaintext: args.insecure_plaintext,
+            dial_timeout: None,
+        },
+    )
+    .await?;
+
+    Ok(Swarm::new(
+        transport,
+        behaviour(client),
+        local_peer_id,
+        swarm::Config::with_async_std_executor(),
+    ))
+}
+
+/// Format a throughput run like the sender's run records.
+fn format_run(params: RunParams, duration: RunDuration, units: ThroughputUnit) -> String {
+    format!(
+        "uploaded {} in {:.4} s ({}), downloaded {} in {:.4} s ({})",
+        format_bytes(params.to_send),
+        duration.upload.as_secs_f64(),
+        format_throughput(params.to_send, duration.upload, units),
+        format_bytes(params.to_receive),
+        duration.download.as_secs_f64(),
+        format_throughput(params.to_receive, duration.download, units),
+    )
+}
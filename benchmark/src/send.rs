@@ -1,46 +1,383 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
-use futures::{future::Either, StreamExt};
+use futures::{FutureExt, StreamExt};
+use ipnet::IpNet;
 use libp2p::{
-    core::{
-        multiaddr::{Multiaddr, Protocol},
-        muxing::StreamMuxerBox,
-        transport::Transport,
-        upgrade,
+    bandwidth::BandwidthSinks,
+    core::multiaddr::{Multiaddr, Protocol},
+    core::Transport,
+    dcutr, identify,
+    identity::Keypair,
+    ping, relay,
+    request_response::{self, ProtocolSupport},
+    swarm::{
+        self,
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, DialError, NetworkBehaviour, Swarm, SwarmEvent,
     },
-    dcutr, dns, identify, noise, ping, quic, relay,
-    swarm::{NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent},
-    tcp, yamux, PeerId,
+    tcp, PeerId, TransportExt,
 };
-use libp2p_perf::{Run, RunParams};
-use log::info;
+use libp2p_perf::{client::RunId, RunDuration, RunParams};
+use log::{info, warn};
+use relay_server::stats;
+use serde_json::json;
 
-use benchmark::{generate_ed25519, swarm_listen, TransportMethod};
+use crate::{
+    anonymize::Anonymizer,
+    baseline::{format_deltas, BaselineCache},
+    build_transport_with,
+    circuit_cache::{CachedCircuit, CircuitCache},
+    configure_runtime,
+    export::{ExporterRegistry, ResultsExporter, RunResult},
+    format_bytes, format_labels, format_rate, format_throughput, generate_ed25519, is_loopback,
+    is_public, is_rate_limited, learn_external_address, parse_cpu_list, parse_duration,
+    parse_label, parse_size, peer_id_from_multiaddr, preferred_addr, read_ticket,
+    schedule::{parse_schedule, Schedule},
+    signal,
+    stalls::{StallStats, WriteStalls},
+    stats::Histogram,
+    swarm_listen,
+    trace::Trace,
+    transfers::Transfer,
+    warning::{Warning, Warnings, MAX_CLOCK_SKEW, MIN_RUN_DURATION},
+    AddrFilter, AddrPreference, Backoff, BdpReport, BenchMode, Capabilities, CommonArgs,
+    ConnectionMode, CpuList, DiscoveryMethod, EventHandling, IdentifyEvents, Preset,
+    ThroughputUnit, TransportMethod, TransportOptions, KEEPALIVE_IDLE_INTERVALS,
+};
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    // Seed used to generate deterministic peer id.
-    #[arg(short, long)]
-    seed: u8,
+/// Behaviours of the sender, reported by `--version --json`.
+pub const BEHAVIOURS: &[&str] = &[
+    "relay-client",
+    "ping",
+    "identify",
+    "dcutr",
+    "perf-client",
+    "signal",
+    "relay-stats",
+];
 
-    // Relay server multi-address.
-    #[arg(short, long)]
-    relay_multiaddr: Multiaddr,
+#[derive(clap::Args, Clone, Debug)]
+pub struct Args {
+    #[command(flatten)]
+    common: CommonArgs,
 
     // Receiver peer ID.
     #[arg(long)]
-    receiver_peer_id: PeerId,
+    receiver_peer_id: Option<PeerId>,
+
+    // Ticket file written by the receiver's --ticket-file.
+    #[arg(long)]
+    ticket_file: Option<PathBuf>,
+
+    // Abort unless the discovered receiver has this peer id, e.g., to catch
+    // a stale ticket now pointing at a different machine.
+    #[arg(long)]
+    expect_peer: Option<PeerId>,
+
+    // Methods to discover the receiver, tried in order until one succeeds
+    // (e.g., ticket,cli).
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "ticket,cli,cache"
+    )]
+    discover: Vec<DiscoveryMethod>,
+
+    // File caching the receiver's circuit address last reached via each
+    // relay, so quick successive invocations skip rediscovery. Defaults to
+    // benchmark-send-circuits.json in the temporary directory.
+    #[arg(long)]
+    circuit_cache: Option<PathBuf>,
+
+    // Age after which a cached circuit address is no longer used.
+    #[arg(long, value_parser = parse_duration, default_value = "10m")]
+    circuit_cache_ttl: Duration,
+
+    // Ignore cached circuit addresses and discover the receiver afresh. The
+    // cache is still updated.
+    #[arg(long)]
+    no_cache: bool,
+
+    // Payload size (e.g., 250MB or 1GiB).
+    #[arg(
+        long,
+        alias = "payload-bytes",
+        value_parser = parse_size,
+        required_unless_present_all = ["upload_bytes", "download_bytes"],
+        default_value_t = 0,
+        hide_default_value = true
+    )]
+    payload: usize,
+
+    // Bytes uploaded per run, overriding --payload, as parameterized by the
+    // libp2p perf spec.
+    #[arg(long, value_parser = parse_size)]
+    upload_bytes: Option<usize>,
+
+    // Bytes downloaded per run, overriding --payload, as parameterized by
+    // the libp2p perf spec.
+    #[arg(long, value_parser = parse_size)]
+    download_bytes: Option<usize>,
+
+    // Start the first run at this wall-clock time (e.g.,
+    // 2024-05-01T12:00:00Z) once ready, so senders across sites start
+    // simultaneously. Requires synchronized clocks.
+    #[arg(long, value_parser = humantime::parse_rfc3339_weak)]
+    start_at: Option<SystemTime>,
+
+    // Write the timeline of hole punching and runs to this file in the
+    // Chrome trace event format, viewable in about://tracing or Perfetto.
+    #[arg(long)]
+    trace: Option<PathBuf>,
 
-    // Payload bytes.
+    // Print one JSON result per throughput run to stdout in the format of
+    // the libp2p perf interop dashboards.
     #[arg(long)]
-    payload_bytes: usize,
+    interop_json: bool,
+
+    // Append one row per throughput run to this CSV file, writing a header
+    // first if the file is new.
+    #[arg(long)]
+    csv: Option<PathBuf>,
+
+    // Export each throughput run to an exporter given as name[:target],
+    // e.g., csv:results.csv, jsonl:results.jsonl, stdout, or interop. May be
+    // repeated.
+    #[arg(long)]
+    export: Vec<String>,
+
+    // Ask the receiver for its own timing of each throughput run and flag
+    // runs where the throughput measured by the two sides diverges by more
+    // than this fraction (e.g., 0.1).
+    #[arg(long)]
+    cross_check: Option<f64>,
+
+    // Sample the bytes transferred at this interval (e.g., 1s) during
+    // throughput runs and log the throughput of each interval, to spot
+    // ramp-up and mid-transfer stalls.
+    #[arg(long, value_parser = parse_duration)]
+    sample_interval: Option<Duration>,
+
+    // Largest payload size accepted by --payload.
+    #[arg(long, value_parser = parse_size, default_value = "10GiB")]
+    max_payload: usize,
+
+    // Also ask this peer (e.g., a second relay server) for our public
+    // address and flag disagreements with the relay's observation.
+    #[arg(long)]
+    observer: Option<Multiaddr>,
+
+    // Number of connections to the relay over which our public address is
+    // observed before the most frequent observation is used, e.g., behind
+    // multi-WAN routers where connections may leave through different
+    // uplinks.
+    #[arg(long, default_value = "1")]
+    address_observations: NonZeroUsize,
+
+    // Only use observed addresses in this network as external addresses
+    // (e.g., 203.0.113.0/24). Repeatable.
+    #[arg(long)]
+    external_addr_filter: Vec<IpNet>,
+
+    // Never use observed addresses in this network as external addresses,
+    // e.g., a VPN's network (e.g., 10.8.0.0/16). Repeatable.
+    #[arg(long)]
+    external_addr_exclude: Vec<IpNet>,
+
+    // Time to wait for the swarm to listen on all interfaces (e.g., 1s).
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    listen_wait: Duration,
+
+    // Use plaintext instead of noise for relayed and TCP connections to
+    // isolate the cost of the security layer. INSECURE: only permitted
+    // when the relay is on loopback.
+    #[arg(long)]
+    insecure_plaintext: bool,
+
+    // Keep TCP_QUICKACK set on TCP connections, i.e., acknowledge every
+    // segment right away instead of delaying ACKs (Linux only). Combine with
+    // --transport tcp-no-delay to disable Nagle's algorithm as well.
+    #[arg(long)]
+    tcp_quickack: bool,
+
+    // Interval of keepalives on idle connections (e.g., 25s): libp2p pings,
+    // TCP keepalive probes (Linux only), and QUIC keep-alive packets.
+    // Connections without active streams stay open for three intervals.
+    // Defaults to libp2p's 15s pings and QUIC keep-alives without TCP
+    // keepalive probes.
+    #[arg(long, value_parser = parse_duration)]
+    keepalive_interval: Option<Duration>,
+
+    // Number of addresses of a peer dialed concurrently.
+    #[arg(long, default_value = "8")]
+    dial_concurrency_factor: NonZeroU8,
+
+    // Timeout for establishing and upgrading an outbound connection,
+    // including circuit dials through the relay (e.g., 30s).
+    #[arg(long, value_parser = parse_duration)]
+    dial_timeout: Option<Duration>,
+
+    // Initial delay before retrying a request the relay refused because one
+    // of its limits was exceeded. Doubles on every retry (e.g., 1s).
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    rate_limit_backoff: Duration,
+
+    // Largest delay between retries of rate limited requests (e.g., 1m).
+    #[arg(long, value_parser = parse_duration, default_value = "1m")]
+    rate_limit_max_backoff: Duration,
+
+    // Number of retries of a rate limited request before giving up.
+    #[arg(long, default_value_t = 5)]
+    rate_limit_retries: usize,
+
+    // Number of times to hole punch again over a fresh relayed connection
+    // after the direct connection upgrade failed, before giving up.
+    #[arg(long, default_value_t = 2)]
+    holepunch_retries: usize,
+
+    // Panic on unexpected swarm events (strict) or log them and continue
+    // (lenient), e.g., for long campaigns.
+    #[arg(long, value_enum, default_value_t=EventHandling::Strict)]
+    event_handling: EventHandling,
+
+    // Number of async runtime worker threads (defaults to the number of CPUs).
+    #[arg(long)]
+    worker_threads: Option<usize>,
+
+    // CPUs to pin the process to (e.g., 0,2-3). Linux only.
+    #[arg(long, value_parser = parse_cpu_list)]
+    cpu_affinity: Option<CpuList>,
+
+    // Curated set of flags for a standardized experiment (smoke, throughput,
+    // latency, or holepunch-campaign). Flags given explicitly override the
+    // preset's.
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
+
+    // Number of benchmark runs to perform, not counting warmup runs.
+    #[arg(long, alias = "iterations", default_value_t = 1)]
+    runs: usize,
+
+    // Throughput runs to perform over the same connection before the
+    // measured runs. Warmup runs are logged but excluded from the throughput
+    // statistics, exports, and baselines.
+    #[arg(long, default_value_t = 0)]
+    warmup: usize,
 
-    // Transport method (tcp or quic-v1).
-    // Should match the transport method of relay_multiaddr.
-    #[arg(short, long, value_enum, default_value_t=TransportMethod::Tcp)]
-    transport: TransportMethod,
+    // Keep the direct connection open across runs (persistent) or close it
+    // after each run and hole punch again for the next (on-demand).
+    #[arg(long, value_enum, default_value_t=ConnectionMode::Persistent)]
+    connection_mode: ConnectionMode,
+
+    // Run the benchmark as a session at each time of this cron-like schedule
+    // in UTC (minute hour day-of-month month day-of-week, e.g., "0 */6 * *
+    // *"), appending each session's results to the exporters, until
+    // interrupted.
+    #[arg(long, value_parser = parse_schedule, conflicts_with = "dry_run")]
+    schedule: Option<Schedule>,
+
+    // Unit of throughput in run records: mbps (10^6 bits/s), MBps (10^6
+    // bytes/s), gbps (10^9 bits/s), or auto.
+    #[arg(long, value_enum, default_value_t=ThroughputUnit::Auto)]
+    units: ThroughputUnit,
+
+    // Report the ratio of upload to download throughput of each throughput
+    // run. The payload is uploaded and then downloaded on the same
+    // connection.
+    #[arg(long)]
+    both_directions: bool,
+
+    // After each throughput run, estimate the bandwidth-delay product from
+    // the direct connection's ping RTT and compare it against the stream
+    // receive window, suggesting a larger window if it limited throughput.
+    #[arg(long)]
+    tuning_advice: bool,
+
+    // Benchmark to run over the direct connection.
+    #[arg(long, value_enum, default_value_t=BenchMode::Throughput)]
+    bench: BenchMode,
+
+    // Number of streams opened per stream-churn run. Each stream transfers
+    // the payload in both directions.
+    #[arg(long, default_value_t = 1000)]
+    churn_streams: usize,
+
+    // Maximum number of concurrently open streams in a stream-churn run.
+    #[arg(long, default_value_t = 64)]
+    churn_concurrency: usize,
+
+    // Number of fresh connections dialed per connection-rate run.
+    #[arg(long, default_value_t = 100)]
+    connections: usize,
+
+    // Idle gaps of nat-binding runs, one run per gap in increasing order
+    // (e.g., 15s,30s,60s).
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        value_delimiter = ',',
+        default_value = "15s,30s,60s,120s,240s"
+    )]
+    idle_gaps: Vec<Duration>,
+
+    // Time to wait for the answer to a nat-binding probe before the binding
+    // is considered lost.
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    probe_timeout: Duration,
+
+    // Validate the configuration, resolve and dial the relay, and print the
+    // benchmark plan without dialing the receiver or transferring data.
+    #[arg(long)]
+    dry_run: bool,
+
+    // Cache the last result of each (receiver, transport, benchmark, payload)
+    // in this JSON file and print how every run compares to it.
+    #[arg(long)]
+    baseline_cache: Option<PathBuf>,
+
+    // Label stored with every run record (e.g., site=berlin). Repeatable.
+    #[arg(long, value_parser = parse_label)]
+    label: Vec<(String, String)>,
+
+    // Instead of benchmarking, hole punch to the receiver via
+    // --relay-multiaddr and each of these relays in turn and compare the
+    // success and setup time per relay. The receiver must hold reservations
+    // on all of them (see its --extra-relay). Repeatable.
+    #[arg(long)]
+    compare_relay: Vec<Multiaddr>,
+
+    // Preferred kinds of address when several direct connections to the
+    // receiver are established, most important first (e.g., quic,ip6).
+    // Connections to less preferred addresses are closed before runs start.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    prefer_addr: Vec<AddrPreference>,
+
+    // Dial the receiver through this relay once the circuit through
+    // --relay-multiaddr fails, e.g., after the receiver failed over to its
+    // --backup-relay.
+    #[arg(long)]
+    backup_relay: Option<Multiaddr>,
+
+    // Time to wait for each hole punch of --compare-relay (e.g., 30s).
+    #[arg(long, value_parser = parse_duration, default_value = "30s")]
+    compare_relay_timeout: Duration,
+
+    // Replace the receiver's peer id and address in run records and
+    // baseline keys with pseudonyms keyed by this secret, e.g., to pool
+    // results without revealing network topology.
+    #[arg(long)]
+    anonymize_key: Option<String>,
+
+    // Maximum bytes sent and received across all runs (e.g., 5GB). No run
+    // is started that would exceed the budget.
+    #[arg(long, value_parser = parse_size)]
+    max_total_bytes: Option<usize>,
 }
 
 #[derive(NetworkBehaviour)]
@@ -50,54 +387,650 @@ struct Behaviour {
     identify: identify::Behaviour,
     dcutr: dcutr::Behaviour,
     perf: libp2p_perf::client::Behaviour,
+    signal: signal::Behaviour,
+    relay_stats: stats::Behaviour,
 }
 
-#[async_std::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format_timestamp_millis()
-        .init();
+impl IdentifyEvents for Behaviour {
+    fn identify_event(event: BehaviourEvent) -> Result<identify::Event, BehaviourEvent> {
+        match event {
+            BehaviourEvent::Identify(event) => Ok(event),
+            event => Err(event),
+        }
+    }
 
-    let args = Args::parse();
+    fn is_ping_event(event: &BehaviourEvent) -> bool {
+        matches!(event, BehaviourEvent::Ping(_))
+    }
+}
+
+impl Args {
+    /// Filter of observed addresses usable as external addresses.
+    fn addr_filter(&self) -> AddrFilter {
+        AddrFilter {
+            include: self.external_addr_filter.clone(),
+            exclude: self.external_addr_exclude.clone(),
+        }
+    }
+
+    /// Bytes uploaded and downloaded per run.
+    fn run_params(&self) -> RunParams {
+        RunParams {
+            to_send: self.upload_bytes.unwrap_or(self.payload),
+            to_receive: self.download_bytes.unwrap_or(self.payload),
+        }
+    }
+
+    /// The larger of the upload and download sizes, which the receiver
+    /// checks against its maximum payload.
+    fn largest_payload(&self) -> usize {
+        let params = self.run_params();
+        params.to_send.max(params.to_receive)
+    }
+
+    /// Name of the run numbered from 1 across warmup and measured runs in
+    /// logs, e.g., `Warmup run 1/2` or `Benchmark run 3/5`.
+    fn run_name(&self, run: usize) -> String {
+        if run <= self.warmup {
+            format!("Warmup run {}/{}", run, self.warmup)
+        } else {
+            format!("Benchmark run {}/{}", run - self.warmup, self.runs)
+        }
+    }
+
+    /// The receiver's peer id, resolved by `discover_receiver` on startup.
+    fn receiver(&self) -> PeerId {
+        self.receiver_peer_id
+            .expect("receiver is discovered before the benchmark starts")
+    }
+}
+
+/// Run the sender until all runs completed.
+pub fn main(args: Args) -> Result<(), Box<dyn Error>> {
+    main_with(args, ExporterRegistry::default())
+}
+
+/// Like [`main`], but resolves `--export` against the given registry so
+/// embedders can add their own results exporters.
+pub fn main_with(args: Args, exporters: ExporterRegistry) -> Result<(), Box<dyn Error>> {
+    configure_runtime(args.worker_threads, args.cpu_affinity.as_ref())?;
+    match args.schedule.clone() {
+        Some(schedule) => async_std::task::block_on(run_scheduled(args, &schedule, exporters)),
+        None => async_std::task::block_on(run(args, &exporters)),
+    }
+}
+
+/// Run a session at each time of `schedule` until interrupted.
+async fn run_scheduled(
+    args: Args,
+    schedule: &Schedule,
+    registry: ExporterRegistry,
+) -> Result<(), Box<dyn Error>> {
+    if args.export.is_empty() && args.csv.is_none() && !args.interop_json {
+        return Err(
+            "--schedule requires --export, --csv, or --interop-json to append results to".into(),
+        );
+    }
+    info!("Schedule: {} (UTC)", schedule);
+    loop {
+        let now = SystemTime::now();
+        let next = schedule
+            .next_after(now)
+            .ok_or_else(|| format!("schedule '{schedule}' never fires"))?;
+        info!(
+            "Next session at {}",
+            humantime::format_rfc3339_seconds(next)
+        );
+        futures_timer::Delay::new(next.duration_since(now).unwrap_or_default()).await;
+        // A failed session, e.g., as the receiver was unreachable, must not
+        // end the monitoring.
+        if let Err(error) = run(args.clone(), &registry).await {
+            warn!("Session failed: {}", error);
+        }
+    }
+}
+
+async fn run(mut args: Args, registry: &ExporterRegistry) -> Result<(), Box<dyn Error>> {
+    let circuit_cache_file = args
+        .circuit_cache
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("benchmark-send-circuits.json"));
+    let mut circuit_cache = CircuitCache::load(&circuit_cache_file)?;
+    let cached_circuit = if args.no_cache {
+        None
+    } else {
+        circuit_cache.get(&args.common.relay_multiaddr, args.circuit_cache_ttl)
+    };
+
+    let receiver = discover_receiver(&args, cached_circuit.as_ref())?;
+    if let Some(expected) = args.expect_peer {
+        if receiver != expected {
+            return Err(format!(
+                "identity mismatch: discovered receiver {receiver} but expected {expected} \
+                 (--expect-peer); the ticket or address may be stale"
+            )
+            .into());
+        }
+    }
+    args.receiver_peer_id = Some(receiver);
+
+    if args.largest_payload() > args.max_payload {
+        return Err(format!(
+            "payload of {} bytes exceeds the maximum of {} bytes (see --max-payload)",
+            args.largest_payload(),
+            args.max_payload
+        )
+        .into());
+    }
+
+    let params = args.run_params();
+    let stream_bytes = params
+        .to_send
+        .checked_add(params.to_receive)
+        .ok_or("upload and download sizes of a run overflow")?;
+    let run_bytes = match args.bench {
+        BenchMode::Throughput | BenchMode::NatBinding => stream_bytes,
+        BenchMode::StreamChurn => stream_bytes
+            .checked_mul(args.churn_streams)
+            .ok_or("bytes per run overflow; reduce --payload or --churn-streams")?,
+        BenchMode::ConnectionRate => 0,
+    };
+    if let Some(max_total_bytes) = args.max_total_bytes {
+        if run_bytes > max_total_bytes {
+            return Err(format!(
+                "a single run transfers {} bytes which exceeds --max-total-bytes of {} bytes",
+                run_bytes, max_total_bytes
+            )
+            .into());
+        }
+    }
+
+    if args.both_directions && args.bench != BenchMode::Throughput {
+        return Err("--both-directions requires --bench throughput".into());
+    }
+    if args.interop_json {
+        args.export.push("interop".to_string());
+    }
+    if let Some(path) = &args.csv {
+        args.export.push(format!("csv:{}", path.display()));
+    }
+    if !args.export.is_empty() && args.bench != BenchMode::Throughput {
+        return Err("--export, --csv, and --interop-json require --bench throughput".into());
+    }
+    let mut exporters = args
+        .export
+        .iter()
+        .map(|spec| registry.create(spec))
+        .collect::<Result<Vec<Box<dyn ResultsExporter>>, _>>()?;
+    if args.cross_check.is_some() && args.bench != BenchMode::Throughput {
+        return Err("--cross-check requires --bench throughput".into());
+    }
+    if args.sample_interval.is_some() && args.bench != BenchMode::Throughput {
+        return Err("--sample-interval requires --bench throughput".into());
+    }
+    if args.warmup > 0
+        && (args.bench != BenchMode::Throughput
+            || args.connection_mode != ConnectionMode::Persistent)
+    {
+        return Err("--warmup requires --bench throughput and --connection-mode persistent".into());
+    }
+    if args.bench == BenchMode::NatBinding {
+        let largest_gap = *args
+            .idle_gaps
+            .iter()
+            .max()
+            .ok_or("--idle-gaps must not be empty")?;
+        if !matches!(args.keepalive_interval, Some(interval) if interval > largest_gap) {
+            return Err(format!(
+                "--bench nat-binding requires a --keepalive-interval longer than the largest \
+                 idle gap of {} on both the sender and receiver (e.g., 1h), so nothing \
+                 refreshes the binding during a gap",
+                humantime::format_duration(largest_gap)
+            )
+            .into());
+        }
+        args.idle_gaps.sort();
+        args.runs = args.idle_gaps.len();
+    }
+    let total_runs = args
+        .warmup
+        .checked_add(args.runs)
+        .ok_or("number of runs overflows; reduce --warmup or --runs")?;
+    // Checked once so the bytes counted over the runs below cannot overflow.
+    if run_bytes.checked_mul(total_runs).is_none() {
+        return Err("total bytes of all runs overflow; reduce --payload or --runs".into());
+    }
 
     info!("DCUTR Bandwidth Benchmark: Sender");
-    info!("Relay multiaddr: {}", args.relay_multiaddr);
-    info!("Transport method: {:?}", args.transport);
+    info!("Relay multiaddr: {}", args.common.relay_multiaddr);
+    info!("Transport method: {:?}", args.common.transport);
+    if let Some(preset) = args.preset {
+        info!("Preset: {:?} ({})", preset, preset.args().join(" "));
+    }
+    info!("Connection mode: {:?}", args.connection_mode);
+    info!("Benchmark: {:?}", args.bench);
+    if !args.label.is_empty() {
+        info!("Labels: {}", format_labels(&args.label));
+    }
 
-    let mut tcp_config = match args.transport {
+    if args.insecure_plaintext {
+        if !is_loopback(&args.common.relay_multiaddr) {
+            return Err("--insecure-plaintext requires a loopback relay multiaddr".into());
+        }
+        if matches!(args.common.transport, TransportMethod::QuicV1) {
+            return Err("--insecure-plaintext is not supported with QUIC".into());
+        }
+    }
+
+    if args.tcp_quickack && matches!(args.common.transport, TransportMethod::QuicV1) {
+        return Err("--tcp-quickack is not supported with QUIC".into());
+    }
+
+    let mut tcp_config = match args.common.transport {
         TransportMethod::TcpNoDelay => tcp::Config::default().nodelay(true),
         TransportMethod::Tcp => tcp::Config::default().nodelay(false),
         _ => tcp::Config::default(),
     };
     tcp_config = tcp_config.port_reuse(true);
 
-    let mut swarm = build_swarm(args.seed, tcp_config).await?;
-    swarm_listen(&mut swarm, args.transport).await?;
-    learn_external_address(&mut swarm, args.relay_multiaddr.clone()).await?;
+    let write_stalls = WriteStalls::default();
+    let (mut swarm, bandwidth) = build_swarm(&args, tcp_config, &write_stalls).await?;
+    let mut trace = args.trace.as_ref().map(|_| Trace::new());
+    let listen_started_at = Instant::now();
+    let phase = tracing::debug_span!("listen", transport = ?args.common.transport);
+    swarm_listen(
+        &mut swarm,
+        args.common.transport,
+        args.listen_wait,
+        args.event_handling,
+    )
+    .await?;
+    drop(phase);
+    let bootstrap_started_at = Instant::now();
+    let phase = tracing::debug_span!("relay bootstrap", relay = %args.common.relay_multiaddr);
+    learn_external_address(
+        &mut swarm,
+        args.common.relay_multiaddr.clone(),
+        args.observer.clone(),
+        args.address_observations,
+        &args.addr_filter(),
+        args.event_handling,
+    )
+    .await?;
+    drop(phase);
+    if let Some(trace) = trace.as_mut() {
+        trace.span(
+            "listen",
+            "phase",
+            0,
+            listen_started_at,
+            bootstrap_started_at - listen_started_at,
+            json!({ "transport": args.common.transport }),
+        );
+        trace.span(
+            "relay bootstrap",
+            "phase",
+            0,
+            bootstrap_started_at,
+            bootstrap_started_at.elapsed(),
+            json!({ "external_addrs": swarm.external_addresses().count() }),
+        );
+    }
+    let mut warnings = Warnings::default();
+    if !swarm.external_addresses().any(is_public) {
+        warnings.push(Warning::NoPublicAddress);
+    }
 
-    swarm
-        .dial(
-            args.relay_multiaddr
-                .with(Protocol::P2pCircuit)
-                .with(Protocol::P2p(args.receiver_peer_id)),
-        )
-        .unwrap();
+    let mut circuit_addr = args
+        .common
+        .relay_multiaddr
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(args.receiver()));
+    if args.dry_run {
+        info!(
+            "Dry run: relay {} is reachable",
+            args.common.relay_multiaddr
+        );
+        info!("Dry run: would dial receiver via {}", circuit_addr);
+        // Runs stop early once the next run would exceed the byte budget.
+        let runs = match args.max_total_bytes {
+            Some(max_total_bytes) if run_bytes > 0 => total_runs.min(max_total_bytes / run_bytes),
+            _ => total_runs,
+        };
+        info!(
+            "Dry run: would perform {} {:?} run(s) over {} and {}, {} bytes per run, {} bytes in total",
+            runs,
+            args.bench,
+            libp2p_perf::PROTOCOL_NAME,
+            args.common.signal_protocol(),
+            run_bytes,
+            runs * run_bytes,
+        );
+        return Ok(());
+    }
+
+    if !args.compare_relay.is_empty() {
+        return compare_relays(&mut swarm, &args).await;
+    }
+
+    let mut relay_addr = args.common.relay_multiaddr.clone();
+    swarm.dial(circuit_addr.clone())?;
+    let mut dialed_at = Instant::now();
+    // Span of the circuit or hole punch phase in progress, if any.
+    let mut phase = circuit_span(&relay_addr);
+    // Span of the run in progress, if any.
+    let mut run_span = tracing::Span::none();
+    let mut circuit_failed_at: Option<Instant> = None;
 
-    let params = RunParams {
-        to_send: args.payload_bytes,
-        to_receive: args.payload_bytes,
+    let mut active_run: Option<ActiveRun> = None;
+    // Whether a relayed connection to the receiver was open during the
+    // active run.
+    let mut run_relayed = false;
+    let mut run_started_at = Instant::now();
+    let mut stalls_at_run_start = StallStats::default();
+    let mut completed_runs = 0;
+    let mut transferred_bytes = 0;
+    // Upload and download throughput of measured throughput runs in bytes
+    // per second.
+    let mut throughput_samples = Vec::new();
+    let mut awaiting_disconnect = false;
+    // Direct connection upgrades failed in a row.
+    let mut holepunch_failures = 0;
+    let mut relayed_connection = None;
+    let mut relayed_path = RelayedPath::default();
+    // Throughput run awaiting the receiver's timing for --cross-check.
+    let mut pending_cross_check: Option<(request_response::RequestId, RunParams, RunDuration)> =
+        None;
+    let mut direct_addr = None;
+    let mut direct_connections: Vec<(ConnectionId, Multiaddr)> = Vec::new();
+    let mut closing_connections: Vec<ConnectionId> = Vec::new();
+    let mut receiver_compatible = false;
+    let mut plan_agreed = false;
+    let mut direct_rtt = None;
+    let local_config = signal::PeerConfig::local(
+        args.common.transport,
+        args.insecure_plaintext,
+        args.tcp_quickack,
+    );
+    let mut config_mismatches = Vec::new();
+    let mut plan_sent_at = SystemTime::now();
+    let mut baselines = args
+        .baseline_cache
+        .as_deref()
+        .map(BaselineCache::load)
+        .transpose()?;
+    let plan = signal::RunPlan {
+        bench: args.bench,
+        payload: args.largest_payload(),
+        runs: total_runs,
+        connection_mode: args.connection_mode,
+        upload_bytes: params.to_send,
+        download_bytes: params.to_receive,
     };
-    let mut started_benchmark = false;
+    let mut backoff = Backoff::new(
+        args.rate_limit_backoff,
+        args.rate_limit_max_backoff,
+        args.rate_limit_retries,
+    );
+    let mut retry_circuit = futures::future::pending().boxed().fuse();
+    let mut start_timer = futures::future::pending().boxed().fuse();
+    let mut probe_timer = futures::future::pending().boxed().fuse();
+    let mut sample_timer = futures::future::pending().boxed().fuse();
+    let mut sampler: Option<ThroughputSampler> = None;
+    // Longest idle gap of nat-binding runs after which the probe was
+    // answered, and whether the binding was lost on the last run.
+    let mut largest_preserved_gap = None;
+    let mut binding_lost = false;
+    let mut waiting_for_start = false;
+    // How late the first run started after --start-at.
+    let mut start_skew = None;
 
     loop {
-        match swarm.next().await.unwrap() {
+        // Keep only the connection to the most preferred direct address so
+        // runs use it.
+        if active_run.is_none() && !args.prefer_addr.is_empty() && direct_connections.len() > 1 {
+            let preferred = preferred_addr(
+                direct_connections.iter().map(|(_, addr)| addr),
+                &args.prefer_addr,
+            )
+            .expect("direct connections exist")
+            .clone();
+            let mut kept = false;
+            direct_connections.retain(|(connection_id, addr)| {
+                if *addr == preferred && !kept {
+                    kept = true;
+                    return true;
+                }
+                info!("Preferring direct address {} over {}", preferred, addr);
+                swarm.close_connection(*connection_id);
+                closing_connections.push(*connection_id);
+                false
+            });
+            direct_addr = Some(preferred);
+        }
+
+        // Start the first run, or the first after hole punching again, once
+        // the relayed connection is gone and the receiver identified itself
+        // and agreed to the run plan. These can complete in any order. The
+        // first run waits for --start-at. Checked before waiting for the next
+        // event so a start timer firing is acted upon.
+        let ready = active_run.is_none()
+            && relayed_connection.is_none()
+            && closing_connections.is_empty()
+            && direct_addr.is_some()
+            && receiver_compatible
+            && plan_agreed;
+        if let Some(start_at) = args.start_at.filter(|_| ready && start_skew.is_none()) {
+            match start_at.duration_since(SystemTime::now()) {
+                Ok(remaining) if !waiting_for_start => {
+                    info!(
+                        "Ready, waiting {} until --start-at",
+                        humantime::format_duration(Duration::from_millis(
+                            remaining.as_millis() as u64
+                        ))
+                    );
+                    start_timer = futures_timer::Delay::new(remaining).boxed().fuse();
+                    waiting_for_start = true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    let skew = e.duration();
+                    info!(
+                        "First run started {:.4} s after --start-at",
+                        skew.as_secs_f64()
+                    );
+                    start_skew = Some(skew);
+                    waiting_for_start = false;
+                }
+            }
+        }
+        if ready && !waiting_for_start {
+            run_started_at = Instant::now();
+            run_span = run_span_for(&args, completed_runs + 1);
+            stalls_at_run_start = write_stalls.snapshot();
+            run_relayed = relayed_connection.is_some();
+            active_run = Some(start_run(
+                &mut swarm,
+                &args,
+                params,
+                &direct_addr,
+                completed_runs,
+            )?);
+        }
+        if let Some(ActiveRun::NatBinding(probe)) = active_run.as_mut() {
+            if let Some(delay) = probe.timer.take() {
+                probe_timer = futures_timer::Delay::new(delay).boxed().fuse();
+            }
+        }
+        if let (Some(interval), Some(ActiveRun::Throughput(..)), None) =
+            (args.sample_interval, &active_run, &sampler)
+        {
+            sampler = Some(ThroughputSampler::new(bandwidth.clone()));
+            sample_timer = futures_timer::Delay::new(interval).boxed().fuse();
+        }
+
+        let mut run_completed = false;
+
+        let event = futures::select! {
+            event = swarm.select_next_some() => event,
+            _ = start_timer => {
+                waiting_for_start = false;
+                continue;
+            }
+            _ = probe_timer => {
+                if let Some(ActiveRun::NatBinding(probe)) = active_run.as_mut() {
+                    if probe.probe.is_none() {
+                        info!(
+                            "Idle for {}, probing the direct connection",
+                            humantime::format_duration(probe.gap)
+                        );
+                        let id = swarm.behaviour_mut().perf.perf(args.receiver(), params)?;
+                        probe.probe = Some((id, Instant::now()));
+                        probe.timer = Some(args.probe_timeout);
+                    } else {
+                        warn!(
+                            "Probe unanswered after {}, closing the direct connection",
+                            humantime::format_duration(args.probe_timeout)
+                        );
+                        probe.timed_out = true;
+                        let _ = swarm.disconnect_peer_id(args.receiver());
+                    }
+                }
+                continue;
+            }
+            _ = sample_timer => {
+                if let Some(sampler) = sampler.as_mut() {
+                    let (at, upload, download) = sampler.sample();
+                    let elapsed = at.duration_since(sampler.run_started_at);
+                    info!(
+                        "{} at {:.1} s: upload {}, download {}",
+                        args.run_name(completed_runs + 1),
+                        elapsed.as_secs_f64(),
+                        format_rate(upload, args.units),
+                        format_rate(download, args.units)
+                    );
+                    if upload == 0.0 && download == 0.0 {
+                        warn!(
+                            "{} stalled: no bytes transferred in the last {}",
+                            args.run_name(completed_runs + 1),
+                            humantime::format_duration(args.sample_interval.unwrap_or_default())
+                        );
+                    }
+                    if let Some(trace) = trace.as_mut() {
+                        trace.counter(
+                            "throughput (Mbit/s)",
+                            at,
+                            json!({ "upload": upload * 8.0 / 1e6, "download": download * 8.0 / 1e6 }),
+                        );
+                    }
+                    let interval = args.sample_interval.expect("sampling requires an interval");
+                    sample_timer = futures_timer::Delay::new(interval).boxed().fuse();
+                }
+                continue;
+            }
+            _ = retry_circuit => {
+                info!("Retrying circuit request (retry {})", backoff.retries());
+                swarm.dial(circuit_addr.clone())?;
+                dialed_at = Instant::now();
+                phase = circuit_span(&relay_addr);
+                continue;
+            }
+        };
+
+        if let SwarmEvent::ConnectionClosed { connection_id, .. } = &event {
+            direct_connections.retain(|(id, _)| id != connection_id);
+            closing_connections.retain(|id| id != connection_id);
+        }
+
+        match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 info!("Listening on {:?}", address);
             }
             SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
-                relay::client::Event::ReservationReqAccepted { .. },
+                event @ relay::client::Event::ReservationReqAccepted { .. },
+            )) => {
+                // Should only happen on receiver side.
+                args.event_handling.unexpected(event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. },
+            )) => {
+                backoff.reset();
+                // The relay only answers the HOP CONNECT request after the
+                // receiver accepted the STOP CONNECT request.
+                info!(
+                    "Relay {} established circuit (HOP sent, STOP accepted) in {:.4} s",
+                    relay_peer_id,
+                    dialed_at.elapsed().as_secs_f64()
+                );
+                circuit_cache.update(&relay_addr, &circuit_addr)?;
+                if let Some(failed_at) = circuit_failed_at.take() {
+                    info!(
+                        "Recovered via backup relay {:.4} s after the circuit failed",
+                        failed_at.elapsed().as_secs_f64()
+                    );
+                }
+                if let Some(trace) = trace.as_mut() {
+                    let relay = relay_peer_id.to_string();
+                    trace.span(
+                        "circuit",
+                        "phase",
+                        0,
+                        dialed_at,
+                        dialed_at.elapsed(),
+                        json!({ "relay": relay }),
+                    );
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitReqFailed {
+                    relay_peer_id,
+                    error,
+                },
+            )) if is_rate_limited(&error) => {
+                let delay = backoff.next_delay().ok_or_else(|| {
+                    format!(
+                        "rate limited by relay: circuit refused after {} retries",
+                        backoff.retries()
+                    )
+                })?;
+                warn!(
+                    "Rate limited by relay {}: circuit refused after {:.4} s, retrying in {}",
+                    relay_peer_id,
+                    dialed_at.elapsed().as_secs_f64(),
+                    humantime::format_duration(delay)
+                );
+                retry_circuit = futures_timer::Delay::new(delay).boxed().fuse();
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitReqFailed {
+                    relay_peer_id,
+                    error,
+                },
             )) => {
-                panic!("Should only happen on receiver side.");
+                info!(
+                    "Relay {} failed to establish circuit after {:.4} s: {:?}",
+                    relay_peer_id,
+                    dialed_at.elapsed().as_secs_f64(),
+                    error
+                );
+                if let Some(addr) = dial_backup_relay(&mut swarm, &args, &mut relay_addr)? {
+                    circuit_addr = addr;
+                    circuit_failed_at = Some(Instant::now());
+                    dialed_at = Instant::now();
+                    phase = circuit_span(&relay_addr);
+                } else if circuit_failed_at.is_some() {
+                    // The receiver may not have failed over to the backup
+                    // relay yet.
+                    let delay = backoff.next_delay().ok_or_else(|| {
+                        format!(
+                            "backup relay refused circuit after {} retries",
+                            backoff.retries()
+                        )
+                    })?;
+                    retry_circuit = futures_timer::Delay::new(delay).boxed().fuse();
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => {
                 info!("{:?}", event)
@@ -105,128 +1038,1506 @@ async fn main() -> Result<(), Box<dyn Error>> {
             SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
                 dcutr::Event::DirectConnectionUpgradeSucceeded { .. },
             )) => {
-                info!("Direct connection upgrade successful!");
-                if !started_benchmark {
-                    swarm
-                        .behaviour_mut()
-                        .perf
-                        .perf(args.receiver_peer_id, params)?;
-                    started_benchmark = true;
+                info!(
+                    "Direct connection upgrade successful in {:.4} s",
+                    dialed_at.elapsed().as_secs_f64()
+                );
+                phase = tracing::Span::none();
+                holepunch_failures = 0;
+                if let Some(trace) = trace.as_mut() {
+                    trace.span(
+                        "hole punch",
+                        "phase",
+                        0,
+                        dialed_at,
+                        dialed_at.elapsed(),
+                        json!({}),
+                    );
+                }
+                // Requests are spread across all connections to a peer, so
+                // close the relayed connection and start the run once it is
+                // gone to keep the run on the direct connection. Signaling
+                // must finish first as it may use the relayed connection.
+                if let Some(connection_id) = relayed_connection.filter(|_| plan_agreed) {
+                    swarm.close_connection(connection_id);
                 }
             }
             SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
                 dcutr::Event::DirectConnectionUpgradeFailed {
                     remote_peer_id: _,
-                    error: e,
+                    error,
                 },
             )) => {
-                panic!("{e:?}")
+                holepunch_failures += 1;
+                phase.record("error", tracing::field::display(&error));
+                phase = tracing::Span::none();
+                warn!(
+                    "Direct connection upgrade failed after {:.4} s (attempt {} of {}): {}",
+                    dialed_at.elapsed().as_secs_f64(),
+                    holepunch_failures,
+                    args.holepunch_retries + 1,
+                    error
+                );
+                if let Some(trace) = trace.as_mut() {
+                    trace.span(
+                        "hole punch",
+                        "phase",
+                        0,
+                        dialed_at,
+                        dialed_at.elapsed(),
+                        json!({ "error": error.to_string() }),
+                    );
+                }
+                if holepunch_failures > args.holepunch_retries {
+                    return Err(format!(
+                        "direct connection upgrade failed {holepunch_failures} times: {error}"
+                    )
+                    .into());
+                }
+                // Hole punch again over a fresh relayed connection once the
+                // current one is closed.
+                if swarm.disconnect_peer_id(args.receiver()).is_ok() {
+                    awaiting_disconnect = true;
+                } else {
+                    swarm.dial(circuit_addr.clone())?;
+                    dialed_at = Instant::now();
+                    phase = circuit_span(&relay_addr);
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => {
                 info!("{:?}", event)
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) if peer_id == args.receiver() => {
+                let capabilities = Capabilities::from_agent_version(&info.agent_version)
+                    .ok_or_else(|| {
+                        format!(
+                            "receiver {} does not advertise benchmark capabilities (agent \
+                             version '{}'); is it running p2p-bench receive?",
+                            peer_id, info.agent_version
+                        )
+                    })?;
+                capabilities.check_compatible(args.bench, args.largest_payload())?;
+                if !receiver_compatible {
+                    info!("Receiver capabilities: {}", info.agent_version);
+                    receiver_compatible = true;
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        response:
+                            signal::Response::Accepted {
+                                plan,
+                                config,
+                                unix_time_ms,
+                            },
+                        ..
+                    },
+                ..
+            })) => {
+                // Assume the receiver answered halfway between request and
+                // response.
+                let local_time_ms = (signal::unix_time_ms(plan_sent_at)
+                    + signal::unix_time_ms(SystemTime::now()))
+                    / 2;
+                let skew = (unix_time_ms as f64 - local_time_ms as f64) / 1000.0;
+                if skew.abs() > MAX_CLOCK_SKEW.as_secs_f64() {
+                    warnings.push(Warning::ClockSkew(skew));
+                }
+                info!("Receiver accepted run plan: {:?}", plan);
+                info!("Sender configuration: {}", local_config);
+                info!("Receiver configuration: {}", config);
+                config_mismatches = local_config.mismatches(&config);
+                if !config_mismatches.is_empty() {
+                    warn!(
+                        "Configuration mismatch with receiver: {}",
+                        config_mismatches.join(", ")
+                    );
+                }
+                plan_agreed = true;
+                if let Some(connection_id) = relayed_connection.filter(|_| direct_addr.is_some()) {
+                    swarm.close_connection(connection_id);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        response: signal::Response::Rejected { reason },
+                        ..
+                    },
+                ..
+            })) => {
+                return Err(format!("receiver rejected the run plan: {reason}").into());
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response: signal::Response::Report { transfer },
+                    },
+                ..
+            })) if pending_cross_check
+                .as_ref()
+                .is_some_and(|(id, ..)| *id == request_id) =>
+            {
+                let (_, params, duration) =
+                    pending_cross_check.take().expect("cross-check is pending");
+                report_cross_check(&args, completed_runs + 1, params, duration, transfer);
+                run_completed = true;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(
+                request_response::Event::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) if pending_cross_check
+                .as_ref()
+                .is_some_and(|(id, ..)| *id == request_id) =>
+            {
+                pending_cross_check = None;
+                warn!(
+                    "{} cross-check failed: {error}",
+                    args.run_name(completed_runs + 1)
+                );
+                run_completed = true;
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(
+                request_response::Event::OutboundFailure {
+                    error: request_response::OutboundFailure::UnsupportedProtocols,
+                    ..
+                },
+            )) => {
+                return Err(format!(
+                    "failed to signal run plan to receiver: it does not support {}; \
+                     check that both use the same --protocol-prefix",
+                    args.common.signal_protocol()
+                )
+                .into());
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(
+                request_response::Event::OutboundFailure { error, .. },
+            )) => {
+                return Err(format!("failed to signal run plan to receiver: {error}").into());
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Signal(event)) => {
+                info!("{:?}", event)
+            }
             SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
                 info!("{:?}", event)
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                peer,
+                connection,
+                result: Ok(rtt),
+            })) if peer == args.receiver() && Some(connection) != relayed_connection => {
+                direct_rtt = Some(rtt);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                peer,
+                connection,
+                result: Ok(rtt),
+            })) if peer == args.receiver()
+                && Some(connection) == relayed_connection
+                && relayed_path.rtt.is_none() =>
+            {
+                relayed_path.rtt = Some(rtt);
+                if relayed_path.legs.is_some() {
+                    info!("Relayed path: {relayed_path}");
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayStats(
+                request_response::Event::Message {
+                    message: request_response::Message::Response { response, .. },
+                    ..
+                },
+            )) => {
+                if let [Some(sender_leg), Some(receiver_leg)] = response.rtts[..] {
+                    relayed_path.legs = Some((sender_leg, receiver_leg));
+                    info!("Relayed path: {relayed_path}");
+                } else {
+                    info!("Relay has not measured the RTT of both legs of the relayed path yet");
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayStats(
+                request_response::Event::OutboundFailure { error, .. },
+            )) => {
+                info!("Relay does not report the RTT of the relayed path's legs: {error}");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayStats(_)) => {}
             SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
+            // Results are attributed by run id, so a late result of an
+            // earlier run or stream is never mistaken for the current one.
             SwarmEvent::Behaviour(BehaviourEvent::Perf(libp2p_perf::client::Event {
-                id: _,
-                result: Ok(duration),
-            })) => {
-                assert!(started_benchmark, "Benchmark not started yet!");
-                info!("Benchmark completed: {}", Run { params, duration });
-                return Ok(());
-            }
+                id,
+                result,
+            })) => match active_run.as_mut() {
+                Some(ActiveRun::Throughput(run_id, run_duration)) if *run_id == id => {
+                    let duration = result.map_err(|e| format!("Benchmark run failed: {e:?}"))?;
+                    *run_duration = Some(duration);
+                    run_span.record("upload_secs", duration.upload.as_secs_f64());
+                    run_span.record("download_secs", duration.download.as_secs_f64());
+                    if let Some(trace) = trace.as_mut() {
+                        let download_started_at = run_started_at + duration.upload;
+                        let (up, down) = (params.to_send, params.to_receive);
+                        trace.span(
+                            "upload",
+                            "transfer",
+                            1,
+                            run_started_at,
+                            duration.upload,
+                            json!({ "bytes": up }),
+                        );
+                        trace.span(
+                            "download",
+                            "transfer",
+                            1,
+                            download_started_at,
+                            duration.download,
+                            json!({ "bytes": down }),
+                        );
+                    }
+                    let asymmetry = if args.both_directions {
+                        format!(
+                            ", asymmetry ratio {:.2} (upload/download)",
+                            asymmetry_ratio(params, duration)
+                        )
+                    } else {
+                        String::new()
+                    };
+                    let mut run_warnings = warnings.clone();
+                    let shortest = duration.upload.min(duration.download);
+                    if shortest < MIN_RUN_DURATION {
+                        run_warnings.push(Warning::PayloadTooSmall(shortest));
+                    }
+                    report_run(
+                        &args,
+                        &direct_addr,
+                        &relayed_path,
+                        &config_mismatches,
+                        &run_warnings,
+                        start_skew.filter(|_| completed_runs == 0),
+                        completed_runs + 1,
+                        format!(
+                            "{}{} (upload {})",
+                            format_run(params, duration, args.units),
+                            asymmetry,
+                            write_stalls.snapshot().since(stalls_at_run_start)
+                        ),
+                    );
+                    let measured = completed_runs >= args.warmup;
+                    if measured {
+                        throughput_samples.push((
+                            params.to_send as f64 / duration.upload.as_secs_f64(),
+                            params.to_receive as f64 / duration.download.as_secs_f64(),
+                        ));
+                    }
+                    let result = RunResult {
+                        finished_at: SystemTime::now(),
+                        transport: args.common.transport,
+                        upload_bytes: params.to_send,
+                        upload: duration.upload,
+                        download_bytes: params.to_receive,
+                        download: duration.download,
+                        relay: match &args.anonymize_key {
+                            Some(key) => Anonymizer::new(key).multiaddr(&relay_addr),
+                            None => relay_addr.to_string(),
+                        },
+                        relayed: run_relayed,
+                    };
+                    for exporter in exporters.iter_mut().filter(|_| measured) {
+                        exporter.export(&result)?;
+                    }
+                    if args.tuning_advice {
+                        match direct_rtt {
+                            Some(rtt) => info!(
+                                "Tuning advice: {}",
+                                BdpReport {
+                                    transport: args.common.transport,
+                                    rtt,
+                                    throughput: throughput(params, duration),
+                                }
+                            ),
+                            None => {
+                                info!("Tuning advice: no RTT measured on the direct connection yet")
+                            }
+                        }
+                    }
+                    if args.cross_check.is_some() {
+                        // Wait for the receiver's timing before the next run,
+                        // so it cannot be mistaken for the next transfer.
+                        let request_id = swarm.behaviour_mut().signal.send_request(
+                            &args.receiver(),
+                            signal::Request::Report {
+                                upload_bytes: params.to_send,
+                                download_bytes: params.to_receive,
+                            },
+                        );
+                        pending_cross_check = Some((request_id, params, duration));
+                    } else {
+                        run_completed = true;
+                    }
+                }
+                Some(ActiveRun::StreamChurn(churn)) if churn.in_flight.contains_key(&id) => {
+                    let opened_at = churn.in_flight.remove(&id).expect("stream is in flight");
+                    if let Some(trace) = trace.as_mut() {
+                        let stream = (completed_runs * args.churn_streams
+                            + churn.succeeded
+                            + churn.failed) as u64;
+                        let ok = result.is_ok();
+                        trace.async_span(
+                            "stream",
+                            "stream",
+                            stream,
+                            opened_at,
+                            json!({ "ok": ok }),
+                        );
+                    }
+                    match result {
+                        Ok(_) => {
+                            churn.succeeded += 1;
+                            churn.latencies.record(opened_at.elapsed());
+                        }
+                        Err(e) => {
+                            info!("Stream failed: {e:?}");
+                            churn.failed += 1;
+                        }
+                    }
+                    if churn.opened < args.churn_streams {
+                        churn.open(&mut swarm, args.receiver(), params)?;
+                    }
+                    if churn.succeeded + churn.failed == args.churn_streams {
+                        report_run(
+                            &args,
+                            &direct_addr,
+                            &relayed_path,
+                            &config_mismatches,
+                            &warnings,
+                            start_skew.filter(|_| completed_runs == 0),
+                            completed_runs + 1,
+                            churn,
+                        );
+                        run_completed = true;
+                    }
+                }
+                Some(ActiveRun::NatBinding(probe))
+                    if probe.probe.map(|(run_id, _)| run_id) == Some(id) =>
+                {
+                    result.map_err(|e| format!("Probe failed: {e:?}"))?;
+                    let (_, sent_at) = probe.probe.expect("probe is in flight");
+                    probe.answered_in = Some(sent_at.elapsed());
+                    probe_timer = futures::future::pending().boxed().fuse();
+                    largest_preserved_gap = Some(probe.gap);
+                    report_run(
+                        &args,
+                        &direct_addr,
+                        &relayed_path,
+                        &config_mismatches,
+                        &warnings,
+                        start_skew.filter(|_| completed_runs == 0),
+                        completed_runs + 1,
+                        &*probe,
+                    );
+                    run_completed = true;
+                }
+                _ => args.event_handling.unexpected((id, result)),
+            },
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
             } => {
                 info!("Established connection to {:?} via {:?}", peer_id, endpoint);
+                if let Some(ActiveRun::ConnectionRate(rate)) = active_run.as_mut() {
+                    if rate.pending_connection() == Some(connection_id) {
+                        swarm.close_connection(connection_id);
+                        let handshake = rate.established();
+                        if let Some(trace) = trace.as_mut() {
+                            let dialed_at = Instant::now() - handshake;
+                            trace.span(
+                                "handshake",
+                                "connection",
+                                1,
+                                dialed_at,
+                                handshake,
+                                json!({}),
+                            );
+                        }
+                        run_completed = rate.next(&mut swarm, args.receiver())?;
+                        if run_completed {
+                            report_run(
+                                &args,
+                                &direct_addr,
+                                &relayed_path,
+                                &config_mismatches,
+                                &warnings,
+                                start_skew.filter(|_| completed_runs == 0),
+                                completed_runs + 1,
+                                rate,
+                            );
+                        }
+                    }
+                }
+                if peer_id == args.receiver() && !endpoint.is_relayed() {
+                    direct_addr.get_or_insert(endpoint.get_remote_address().clone());
+                    direct_connections.push((connection_id, endpoint.get_remote_address().clone()));
+                }
+                if peer_id == args.receiver() && endpoint.is_relayed() {
+                    relayed_connection = Some(connection_id);
+                    phase = tracing::debug_span!(
+                        "hole punch",
+                        relay = %relay_addr,
+                        error = tracing::field::Empty
+                    );
+                    info!(
+                        "Relayed connection to receiver established in {:.4} s",
+                        dialed_at.elapsed().as_secs_f64()
+                    );
+                    // Ask the relay for its RTT to both ends to split the
+                    // relayed path into its legs.
+                    relayed_path = RelayedPath::default();
+                    if let Some(relay) = peer_id_from_multiaddr(&relay_addr) {
+                        let peers = vec![swarm.local_peer_id().to_string(), peer_id.to_string()];
+                        swarm
+                            .behaviour_mut()
+                            .relay_stats
+                            .send_request(&relay, stats::Request { peers });
+                    }
+                    if active_run.is_some() {
+                        warnings.push(Warning::RelayedConnection);
+                        run_relayed = true;
+                    }
+                    if !plan_agreed {
+                        plan_sent_at = SystemTime::now();
+                        swarm.behaviour_mut().signal.send_request(
+                            &peer_id,
+                            signal::Request::Propose {
+                                plan: plan.clone(),
+                                config: local_config.clone(),
+                            },
+                        );
+                    }
+                }
+            }
+            SwarmEvent::ConnectionClosed { connection_id, .. }
+                if relayed_connection == Some(connection_id) =>
+            {
+                relayed_connection = None;
             }
-            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } if peer_id == args.receiver()
+                && matches!(active_run, Some(ActiveRun::NatBinding(_))) =>
+            {
+                if let Some(ActiveRun::NatBinding(probe)) = active_run.as_ref() {
+                    probe_timer = futures::future::pending().boxed().fuse();
+                    binding_lost = true;
+                    report_run(
+                        &args,
+                        &direct_addr,
+                        &relayed_path,
+                        &config_mismatches,
+                        &warnings,
+                        start_skew.filter(|_| completed_runs == 0),
+                        completed_runs + 1,
+                        probe,
+                    );
+                    run_completed = true;
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } if awaiting_disconnect && peer_id == args.receiver() => {
+                info!(
+                    "Closed all connections to {:?}, hole punching again",
+                    peer_id
+                );
+                awaiting_disconnect = false;
+                swarm.dial(circuit_addr.clone())?;
+                dialed_at = Instant::now();
+                phase = circuit_span(&relay_addr);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                error: DialError::WrongPeerId { obtained, endpoint },
+                ..
+            } => {
+                return Err(format!(
+                    "identity mismatch: {} belongs to {} instead of the receiver {}",
+                    endpoint.get_remote_address(),
+                    obtained,
+                    args.receiver()
+                )
+                .into());
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
                 info!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+                let circuit_dial_failed = matches!(&error, DialError::Transport(errors)
+                    if errors.iter().any(|(addr, _)| *addr == circuit_addr));
+                if circuit_dial_failed && relayed_connection.is_none() {
+                    if let Some(addr) = dial_backup_relay(&mut swarm, &args, &mut relay_addr)? {
+                        circuit_addr = addr;
+                        circuit_failed_at = Some(Instant::now());
+                        dialed_at = Instant::now();
+                        phase = circuit_span(&relay_addr);
+                    }
+                }
+                if let Some(ActiveRun::ConnectionRate(rate)) = active_run.as_mut() {
+                    if rate.pending_connection() == Some(connection_id) {
+                        rate.failed();
+                        run_completed = rate.next(&mut swarm, args.receiver())?;
+                        if run_completed {
+                            report_run(
+                                &args,
+                                &direct_addr,
+                                &relayed_path,
+                                &config_mismatches,
+                                &warnings,
+                                start_skew.filter(|_| completed_runs == 0),
+                                completed_runs + 1,
+                                rate,
+                            );
+                        }
+                    }
+                }
             }
             _ => {}
         }
+
+        if !run_completed {
+            continue;
+        }
+        let measured = completed_runs >= args.warmup;
+        if let (Some(baselines), Some(run), true) =
+            (baselines.as_mut(), active_run.as_ref(), measured)
+        {
+            let receiver = match &args.anonymize_key {
+                Some(key) => Anonymizer::new(key).peer_id(&args.receiver()),
+                None => args.receiver().to_string(),
+            };
+            let payload = if params.to_send == params.to_receive {
+                params.to_send.to_string()
+            } else {
+                format!("{}+{}", params.to_send, params.to_receive)
+            };
+            let key = format!(
+                "{}/{:?}/{:?}/{}",
+                receiver, args.common.transport, args.bench, payload
+            );
+            let metrics = run.metrics(params);
+            match baselines.update(&key, &metrics)? {
+                Some(previous) => info!(
+                    "Compared to previous run at {}: {}",
+                    previous.recorded_at,
+                    format_deltas(&previous, &metrics)
+                ),
+                None => info!("No previous run to compare against, recorded baseline"),
+            }
+        }
+        if let (Some(trace), Some(path)) = (trace.as_mut(), &args.trace) {
+            let name = format!("run {}", completed_runs + 1);
+            trace.span(
+                name,
+                "run",
+                1,
+                run_started_at,
+                run_started_at.elapsed(),
+                json!({}),
+            );
+            trace.save(path)?;
+        }
+        active_run = None;
+        run_span = tracing::Span::none();
+        sampler = None;
+        sample_timer = futures::future::pending().boxed().fuse();
+        completed_runs += 1;
+        transferred_bytes += run_bytes;
+
+        if args.bench == BenchMode::NatBinding && (binding_lost || completed_runs >= args.runs) {
+            let lost_gap = binding_lost.then(|| args.idle_gaps[completed_runs - 1]);
+            report_binding_timeout(largest_preserved_gap, lost_gap);
+            return Ok(());
+        }
+
+        if completed_runs >= total_runs {
+            report_throughput_stats(&throughput_samples, args.units);
+            return Ok(());
+        }
+
+        if let Some(max_total_bytes) = args.max_total_bytes {
+            if transferred_bytes + run_bytes > max_total_bytes {
+                info!(
+                    "Stopping after {} runs: next run would exceed byte budget ({} of {} bytes used)",
+                    completed_runs, transferred_bytes, max_total_bytes
+                );
+                report_throughput_stats(&throughput_samples, args.units);
+                return Ok(());
+            }
+        }
+
+        match args.connection_mode {
+            ConnectionMode::Persistent => {
+                run_started_at = Instant::now();
+                run_span = run_span_for(&args, completed_runs + 1);
+                stalls_at_run_start = write_stalls.snapshot();
+                run_relayed = relayed_connection.is_some();
+                active_run = Some(start_run(
+                    &mut swarm,
+                    &args,
+                    params,
+                    &direct_addr,
+                    completed_runs,
+                )?);
+            }
+            ConnectionMode::OnDemand => {
+                // Close the relayed and direct connections. The circuit
+                // is dialed again once all connections are closed, or right
+                // away if the receiver already disconnected.
+                if swarm.disconnect_peer_id(args.receiver()).is_ok() {
+                    awaiting_disconnect = true;
+                } else {
+                    info!("Receiver already disconnected, hole punching again");
+                    swarm.dial(circuit_addr.clone())?;
+                    dialed_at = Instant::now();
+                    phase = circuit_span(&relay_addr);
+                }
+                direct_addr = None;
+            }
+        }
+    }
+}
+
+/// Log the NAT binding timeout bracketed by the idle gaps of nat-binding runs
+/// after which the binding was preserved and lost.
+fn report_binding_timeout(preserved: Option<Duration>, lost: Option<Duration>) {
+    let format = humantime::format_duration;
+    match (preserved, lost) {
+        (Some(preserved), Some(lost)) => info!(
+            "NAT binding timeout is between {} and {}: keep --keepalive-interval below {}",
+            format(preserved),
+            format(lost),
+            format(preserved)
+        ),
+        (None, Some(lost)) => info!(
+            "NAT binding timeout is below the shortest idle gap of {}",
+            format(lost)
+        ),
+        (Some(preserved), None) => info!(
+            "NAT binding preserved for the longest idle gap of {}",
+            format(preserved)
+        ),
+        (None, None) => {}
+    }
+}
+
+/// Dial the receiver via `--backup-relay` unless `relay_addr` already is the
+/// backup relay, returning the new circuit address.
+fn dial_backup_relay(
+    swarm: &mut Swarm<Behaviour>,
+    args: &Args,
+    relay_addr: &mut Multiaddr,
+) -> Result<Option<Multiaddr>, Box<dyn Error>> {
+    let backup_relay = match &args.backup_relay {
+        Some(relay) if relay != relay_addr => relay,
+        _ => return Ok(None),
+    };
+    info!("Dialing receiver via backup relay {}", backup_relay);
+    *relay_addr = backup_relay.clone();
+    let circuit_addr = backup_relay
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(args.receiver()));
+    swarm.dial(circuit_addr.clone())?;
+    Ok(Some(circuit_addr))
+}
+
+/// Span of a circuit request to the receiver via `relay`, which lasts until
+/// the relayed connection is established.
+fn circuit_span(relay: &Multiaddr) -> tracing::Span {
+    tracing::debug_span!("circuit", relay = %relay)
+}
+
+/// Span of the run numbered `run`, which lasts until the run completed.
+/// Throughput runs record the duration of each direction.
+fn run_span_for(args: &Args, run: usize) -> tracing::Span {
+    tracing::debug_span!(
+        "run",
+        run,
+        bench = ?args.bench,
+        upload_secs = tracing::field::Empty,
+        download_secs = tracing::field::Empty
+    )
+}
+
+/// Hole punch to the receiver via `--relay-multiaddr` and each of
+/// `--compare-relay` in turn, closing all connections to the receiver in
+/// between, and report the outcome and setup time per relay.
+async fn compare_relays(swarm: &mut Swarm<Behaviour>, args: &Args) -> Result<(), Box<dyn Error>> {
+    let relays: Vec<Multiaddr> = std::iter::once(args.common.relay_multiaddr.clone())
+        .chain(args.compare_relay.iter().cloned())
+        .collect();
+    let receiver = args.receiver();
+    let mut results = Vec::new();
+    for relay in relays {
+        let circuit_addr = relay
+            .clone()
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(receiver));
+        info!("Hole punching via relay {}", relay);
+        swarm.dial(circuit_addr)?;
+        let dialed_at = Instant::now();
+        let mut timeout = futures_timer::Delay::new(args.compare_relay_timeout).fuse();
+        let outcome = loop {
+            let event = futures::select! {
+                event = swarm.select_next_some() => event,
+                _ = timeout => break Err("timed out".to_string()),
+            };
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                    dcutr::Event::DirectConnectionUpgradeSucceeded { .. },
+                )) => break Ok(dialed_at.elapsed()),
+                SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                    dcutr::Event::DirectConnectionUpgradeFailed { error, .. },
+                )) => break Err(format!("hole punch failed: {error}")),
+                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                    relay::client::Event::OutboundCircuitReqFailed { error, .. },
+                )) => break Err(format!("circuit refused: {error:?}")),
+                SwarmEvent::OutgoingConnectionError {
+                    peer_id: Some(peer_id),
+                    error,
+                    ..
+                } if peer_id == receiver => break Err(format!("dial failed: {error}")),
+                _ => {}
+            }
+        };
+        match &outcome {
+            Ok(elapsed) => info!(
+                "Hole punch via relay {} succeeded in {:.4} s",
+                relay,
+                elapsed.as_secs_f64()
+            ),
+            Err(e) => info!("Hole punch via relay {} failed: {}", relay, e),
+        }
+        results.push((relay, outcome));
+
+        // Start the next attempt without any connection to the receiver.
+        if swarm.disconnect_peer_id(receiver).is_ok() {
+            loop {
+                if let SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } = swarm.select_next_some().await
+                {
+                    if peer_id == receiver {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    info!("Relay comparison:");
+    for (relay, outcome) in results {
+        match outcome {
+            Ok(elapsed) => info!("  {}: succeeded in {:.4} s", relay, elapsed.as_secs_f64()),
+            Err(e) => info!("  {}: {}", relay, e),
+        }
+    }
+    Ok(())
+}
+
+/// Find the receiver's peer id with the first of `args.discover` that
+/// succeeds.
+fn discover_receiver(
+    args: &Args,
+    cached_circuit: Option<&CachedCircuit>,
+) -> Result<PeerId, Box<dyn Error>> {
+    for method in &args.discover {
+        let result = match method {
+            DiscoveryMethod::Ticket => match &args.ticket_file {
+                Some(path) => read_ticket(path).and_then(|addr| {
+                    let relay: Multiaddr = addr
+                        .iter()
+                        .take_while(|p| *p != Protocol::P2pCircuit)
+                        .collect();
+                    if relay != args.common.relay_multiaddr {
+                        return Err(
+                            format!("ticket is for relay {relay}, not --relay-multiaddr").into(),
+                        );
+                    }
+                    Ok(peer_id_from_multiaddr(&addr).expect("ticket ends with a peer id"))
+                }),
+                None => Err("no --ticket-file given".into()),
+            },
+            DiscoveryMethod::Cli => args
+                .receiver_peer_id
+                .ok_or_else(|| "no --receiver-peer-id given".into()),
+            DiscoveryMethod::Cache => match cached_circuit {
+                Some(cached) => {
+                    info!(
+                        "Using circuit address {} cached {} ago",
+                        cached.circuit_addr,
+                        humantime::format_duration(Duration::from_secs(cached.age.as_secs()))
+                    );
+                    Ok(peer_id_from_multiaddr(&cached.circuit_addr)
+                        .expect("circuit address ends with a peer id"))
+                }
+                None => Err("no fresh circuit address cached for --relay-multiaddr".into()),
+            },
+        };
+        match result {
+            Ok(peer_id) => {
+                info!("Discovered receiver {} via {:?}", peer_id, method);
+                return Ok(peer_id);
+            }
+            Err(e) => info!("Discovery via {:?} failed: {}", method, e),
+        }
+    }
+    Err(format!(
+        "could not discover the receiver via any of {:?}; pass --receiver-peer-id or --ticket-file",
+        args.discover
+    )
+    .into())
+}
+
+/// Log the record of a completed run, annotated with the run's labels, the
+/// receiver and its direct address, any settings that differ between sender
+/// and receiver, and any warnings.
+#[allow(clippy::too_many_arguments)]
+fn report_run(
+    args: &Args,
+    direct_addr: &Option<Multiaddr>,
+    relayed_path: &RelayedPath,
+    config_mismatches: &[&str],
+    warnings: &Warnings,
+    start_skew: Option<Duration>,
+    run: usize,
+    record: impl std::fmt::Display,
+) {
+    let labels = if args.label.is_empty() {
+        String::new()
+    } else {
+        format!(" [{}]", format_labels(&args.label))
+    };
+    let path = match &args.anonymize_key {
+        Some(key) => {
+            let anonymizer = Anonymizer::new(key);
+            format!(
+                " (receiver {} at {})",
+                anonymizer.peer_id(&args.receiver()),
+                direct_addr
+                    .as_ref()
+                    .map_or("unknown".to_string(), |addr| anonymizer.multiaddr(addr))
+            )
+        }
+        None => format!(
+            " (receiver {} at {})",
+            args.receiver(),
+            direct_addr
+                .as_ref()
+                .map_or("unknown".to_string(), |addr| addr.to_string())
+        ),
+    };
+    let relayed_path = if relayed_path.is_empty() {
+        String::new()
+    } else {
+        format!(" (relayed path: {})", relayed_path)
+    };
+    let mismatches = if config_mismatches.is_empty() {
+        String::new()
+    } else {
+        format!(" (config mismatch: {})", config_mismatches.join(", "))
+    };
+    let warnings = if warnings.is_empty() {
+        String::new()
+    } else {
+        format!(" (warnings: {})", warnings)
+    };
+    let start_skew = match start_skew {
+        Some(skew) => format!(" (start skew {:.4} s)", skew.as_secs_f64()),
+        None => String::new(),
+    };
+    info!(
+        "{} completed: {}{}{}{}{}{}{}",
+        args.run_name(run),
+        record,
+        labels,
+        path,
+        relayed_path,
+        mismatches,
+        warnings,
+        start_skew
+    );
+}
+
+/// Log the receiver's timing of a throughput run and flag the run if the
+/// throughput measured by the two sides diverges by more than --cross-check,
+/// which usually indicates buffering artifacts or timer issues on one host.
+fn report_cross_check(
+    args: &Args,
+    run: usize,
+    params: RunParams,
+    duration: RunDuration,
+    transfer: Option<Transfer>,
+) {
+    let Some(transfer) = transfer else {
+        warn!(
+            "{} cross-check: receiver found no matching transfer",
+            args.run_name(run)
+        );
+        return;
+    };
+    info!(
+        "{} cross-check: receiver measured upload in {:.4} s ({}), download in {:.4} s ({})",
+        args.run_name(run),
+        transfer.upload.as_secs_f64(),
+        format_throughput(params.to_send, transfer.upload, args.units),
+        transfer.download.as_secs_f64(),
+        format_throughput(params.to_receive, transfer.download, args.units),
+    );
+    if let Some(peak_rss) = transfer.peak_rss {
+        info!(
+            "{} cross-check: receiver peak resident set size while throttling reads: {}",
+            args.run_name(run),
+            format_bytes(peak_rss as usize)
+        );
+    }
+    let tolerance = args.cross_check.unwrap_or_default();
+    for (direction, sender, receiver) in [
+        ("upload", duration.upload, transfer.upload),
+        ("download", duration.download, transfer.download),
+    ] {
+        // Both sides transferred the same bytes, so the throughputs diverge
+        // by the inverse ratio of the durations.
+        let divergence = (sender.as_secs_f64() / receiver.as_secs_f64() - 1.0).abs();
+        if divergence > tolerance {
+            warn!(
+                "{} flagged: sender- and receiver-measured {} throughput diverge by {:.0}%",
+                args.run_name(run),
+                direction,
+                divergence * 100.0
+            );
+        }
+    }
+}
+
+/// Log the mean, median, standard deviation, and range of the upload and
+/// download throughput of the measured runs, if there was more than one.
+fn report_throughput_stats(samples: &[(f64, f64)], units: ThroughputUnit) {
+    if samples.len() < 2 {
+        return;
+    }
+    let uploads: Vec<_> = samples.iter().map(|(upload, _)| *upload).collect();
+    let downloads: Vec<_> = samples.iter().map(|(_, download)| *download).collect();
+    for (direction, mut values) in [("Upload", uploads), ("Download", downloads)] {
+        values.sort_by(f64::total_cmp);
+        let n = values.len();
+        let mean = values.iter().sum::<f64>() / n as f64;
+        let median = if n % 2 == 0 {
+            (values[n / 2 - 1] + values[n / 2]) / 2.0
+        } else {
+            values[n / 2]
+        };
+        // Sample standard deviation, as the runs sample the path's throughput.
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        info!(
+            "{} throughput over {} runs: mean {}, median {}, stddev {}, min {}, max {}",
+            direction,
+            n,
+            format_rate(mean, units),
+            format_rate(median, units),
+            format_rate(variance.sqrt(), units),
+            format_rate(values[0], units),
+            format_rate(values[n - 1], units),
+        );
+    }
+}
+
+/// Format a throughput run, e.g., `uploaded 976.56 KiB in 0.0808 s
+/// (96.69 Mbit/s), downloaded ...`.
+fn format_run(params: RunParams, duration: RunDuration, units: ThroughputUnit) -> String {
+    format!(
+        "uploaded {} in {:.4} s ({}), downloaded {} in {:.4} s ({})",
+        format_bytes(params.to_send),
+        duration.upload.as_secs_f64(),
+        format_throughput(params.to_send, duration.upload, units),
+        format_bytes(params.to_receive),
+        duration.download.as_secs_f64(),
+        format_throughput(params.to_receive, duration.download, units),
+    )
+}
+
+/// Throughput of the faster direction of a run in bytes per second.
+fn throughput(params: RunParams, duration: RunDuration) -> f64 {
+    let upload = params.to_send as f64 / duration.upload.as_secs_f64();
+    let download = params.to_receive as f64 / duration.download.as_secs_f64();
+    upload.max(download)
+}
+
+/// Ratio of upload to download throughput of a run, above 1 if uploading
+/// was faster.
+fn asymmetry_ratio(params: RunParams, duration: RunDuration) -> f64 {
+    let upload = params.to_send as f64 / duration.upload.as_secs_f64();
+    let download = params.to_receive as f64 / duration.download.as_secs_f64();
+    upload / download
+}
+
+/// Samples of the bytes transferred during a throughput run, from the
+/// transport's counters. These count all connections, including protocol
+/// overhead, which a throughput run dominates.
+struct ThroughputSampler {
+    bandwidth: Arc<BandwidthSinks>,
+    run_started_at: Instant,
+    // When the previous sample was taken and the bytes sent and received
+    // until then.
+    last: (Instant, u64, u64),
+}
+
+impl ThroughputSampler {
+    fn new(bandwidth: Arc<BandwidthSinks>) -> Self {
+        let now = Instant::now();
+        let last = (now, bandwidth.total_outbound(), bandwidth.total_inbound());
+        ThroughputSampler {
+            bandwidth,
+            run_started_at: now,
+            last,
+        }
+    }
+
+    /// Take a sample, returning when it was taken and the upload and
+    /// download throughput since the previous sample in bytes per second.
+    fn sample(&mut self) -> (Instant, f64, f64) {
+        let now = Instant::now();
+        let (sent, received) = (
+            self.bandwidth.total_outbound(),
+            self.bandwidth.total_inbound(),
+        );
+        let (last_at, last_sent, last_received) = self.last;
+        let seconds = now.duration_since(last_at).as_secs_f64();
+        self.last = (now, sent, received);
+        (
+            now,
+            (sent - last_sent) as f64 / seconds,
+            (received - last_received) as f64 / seconds,
+        )
+    }
+}
+
+/// Progress of a stream-churn run.
+struct StreamChurn {
+    started: Instant,
+    opened: usize,
+    // When each stream in flight was opened.
+    in_flight: HashMap<RunId, Instant>,
+    succeeded: usize,
+    failed: usize,
+    // From opening each successful stream until its result.
+    latencies: Histogram,
+}
+
+impl StreamChurn {
+    fn open(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        receiver: PeerId,
+        params: RunParams,
+    ) -> Result<(), Box<dyn Error>> {
+        let id = swarm.behaviour_mut().perf.perf(receiver, params)?;
+        self.in_flight.insert(id, Instant::now());
+        self.opened += 1;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for StreamChurn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let total = self.succeeded + self.failed;
+        write!(
+            f,
+            "{} streams in {:.4} s ({:.2} streams/s), {} failed ({:.2}% error rate)",
+            total,
+            elapsed,
+            total as f64 / elapsed,
+            self.failed,
+            100.0 * self.failed as f64 / total as f64,
+        )?;
+        if !self.latencies.is_empty() {
+            write!(f, ", stream latency {}", self.latencies)?;
+        }
+        Ok(())
+    }
+}
+
+/// Progress of a connection-rate run, which dials fresh direct connections
+/// to the receiver one at a time.
+struct ConnectionRate {
+    addr: Multiaddr,
+    connections: usize,
+    started: Instant,
+    pending: Option<(ConnectionId, Instant)>,
+    handshakes: Histogram,
+    failed: usize,
+}
+
+impl ConnectionRate {
+    fn pending_connection(&self) -> Option<ConnectionId> {
+        self.pending.map(|(connection_id, _)| connection_id)
+    }
+
+    /// Record the pending connection as established, returning how long
+    /// its handshake took.
+    fn established(&mut self) -> Duration {
+        let (_, dialed_at) = self.pending.take().expect("a dial is pending");
+        let handshake = dialed_at.elapsed();
+        self.handshakes.record(handshake);
+        handshake
+    }
+
+    fn failed(&mut self) {
+        self.pending = None;
+        self.failed += 1;
+    }
+
+    /// Dial the next connection, returning true if the run is complete.
+    fn next(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        receiver: PeerId,
+    ) -> Result<bool, Box<dyn Error>> {
+        if self.handshakes.len() + self.failed == self.connections {
+            return Ok(true);
+        }
+        let opts = DialOpts::peer_id(receiver)
+            .addresses(vec![self.addr.clone()])
+            .condition(PeerCondition::Always)
+            .build();
+        self.pending = Some((opts.connection_id(), Instant::now()));
+        swarm.dial(opts)?;
+        Ok(false)
+    }
+}
+
+impl std::fmt::Display for ConnectionRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let total = self.handshakes.len() + self.failed;
+        write!(
+            f,
+            "{} connections in {:.4} s ({:.2} connections/s), {} failed",
+            total,
+            elapsed,
+            total as f64 / elapsed,
+            self.failed,
+        )?;
+        if !self.handshakes.is_empty() {
+            write!(f, ", handshake latency {}", self.handshakes)?;
+        }
+        Ok(())
+    }
+}
+
+/// A nat-binding run: the direct connection is left idle for `gap`, then
+/// probed with a perf run of the payload.
+struct IdleProbe {
+    gap: Duration,
+    /// Delay until the next step, armed by the main loop: the end of the gap,
+    /// then the probe timeout.
+    timer: Option<Duration>,
+    /// The probe in flight and when it was sent.
+    probe: Option<(RunId, Instant)>,
+    answered_in: Option<Duration>,
+    /// The probe was not answered within --probe-timeout.
+    timed_out: bool,
+}
+
+impl std::fmt::Display for IdleProbe {
+    /// Format a nat-binding run, e.g., `binding preserved after 30s idle,
+    /// probe answered in 0.0012 s`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gap = humantime::format_duration(self.gap);
+        match self.answered_in {
+            Some(answered_in) => write!(
+                f,
+                "binding preserved after {} idle, probe answered in {:.4} s",
+                gap,
+                answered_in.as_secs_f64()
+            ),
+            None if self.timed_out => write!(
+                f,
+                "binding lost after {} idle, probe unanswered within --probe-timeout",
+                gap
+            ),
+            None => write!(f, "connection closed during the {} idle gap", gap),
+        }
+    }
+}
+
+/// RTT of the relayed path to the receiver, measured by pinging over the
+/// relayed connection, and of its legs as reported by the relay.
+#[derive(Clone, Copy, Debug, Default)]
+struct RelayedPath {
+    rtt: Option<Duration>,
+    /// The relay's RTT to the sender and to the receiver.
+    legs: Option<(Duration, Duration)>,
+}
+
+impl RelayedPath {
+    fn is_empty(&self) -> bool {
+        self.rtt.is_none() && self.legs.is_none()
+    }
+}
+
+impl std::fmt::Display for RelayedPath {
+    /// Format as, e.g., `RTT 0.1200 s, sender-relay 0.0800 s, relay-receiver
+    /// 0.0350 s, relay forwarding 0.0050 s, sender-relay leg dominates`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(rtt) = self.rtt {
+            parts.push(format!("RTT {:.4} s", rtt.as_secs_f64()));
+        }
+        if let Some((sender_leg, receiver_leg)) = self.legs {
+            parts.push(format!("sender-relay {:.4} s", sender_leg.as_secs_f64()));
+            parts.push(format!(
+                "relay-receiver {:.4} s",
+                receiver_leg.as_secs_f64()
+            ));
+            // The legs are measured by the relay's own pings, so the
+            // remainder of the relayed RTT is spent forwarding.
+            if let Some(forwarding) = self
+                .rtt
+                .and_then(|rtt| rtt.checked_sub(sender_leg + receiver_leg))
+            {
+                parts.push(format!(
+                    "relay forwarding {:.4} s",
+                    forwarding.as_secs_f64()
+                ));
+            }
+            parts.push(format!(
+                "{} leg dominates",
+                if sender_leg >= receiver_leg {
+                    "sender-relay"
+                } else {
+                    "relay-receiver"
+                }
+            ));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// A benchmark run in progress.
+enum ActiveRun {
+    Throughput(RunId, Option<RunDuration>),
+    StreamChurn(StreamChurn),
+    ConnectionRate(ConnectionRate),
+    NatBinding(IdleProbe),
+}
+
+impl ActiveRun {
+    /// Headline metrics of a completed run, compared against baselines.
+    fn metrics(&self, params: RunParams) -> Vec<(&'static str, f64)> {
+        match self {
+            ActiveRun::Throughput(_, Some(duration)) => vec![
+                (
+                    "upload Mbit/s",
+                    params.to_send as f64 * 8.0 / duration.upload.as_secs_f64() / 1e6,
+                ),
+                (
+                    "download Mbit/s",
+                    params.to_receive as f64 * 8.0 / duration.download.as_secs_f64() / 1e6,
+                ),
+            ],
+            ActiveRun::Throughput(_, None) => vec![],
+            ActiveRun::StreamChurn(churn) => {
+                let total = (churn.succeeded + churn.failed) as f64;
+                vec![
+                    ("streams/s", total / churn.started.elapsed().as_secs_f64()),
+                    ("error rate %", 100.0 * churn.failed as f64 / total),
+                ]
+            }
+            ActiveRun::ConnectionRate(rate) => {
+                let total = (rate.handshakes.len() + rate.failed) as f64;
+                vec![(
+                    "connections/s",
+                    total / rate.started.elapsed().as_secs_f64(),
+                )]
+            }
+            ActiveRun::NatBinding(_) => vec![],
+        }
+    }
+}
+
+/// Start a benchmark run over the direct connection to the receiver.
+fn start_run(
+    swarm: &mut Swarm<Behaviour>,
+    args: &Args,
+    params: RunParams,
+    direct_addr: &Option<Multiaddr>,
+    completed_runs: usize,
+) -> Result<ActiveRun, Box<dyn Error>> {
+    match args.bench {
+        BenchMode::NatBinding => {
+            let gap = args.idle_gaps[completed_runs];
+            info!(
+                "Leaving the direct connection idle for {}",
+                humantime::format_duration(gap)
+            );
+            Ok(ActiveRun::NatBinding(IdleProbe {
+                gap,
+                timer: Some(gap),
+                probe: None,
+                answered_in: None,
+                timed_out: false,
+            }))
+        }
+        BenchMode::Throughput => {
+            let id = swarm.behaviour_mut().perf.perf(args.receiver(), params)?;
+            Ok(ActiveRun::Throughput(id, None))
+        }
+        BenchMode::StreamChurn => {
+            let mut churn = StreamChurn {
+                started: Instant::now(),
+                opened: 0,
+                in_flight: HashMap::new(),
+                succeeded: 0,
+                failed: 0,
+                latencies: Histogram::default(),
+            };
+            while churn.opened < args.churn_streams.min(args.churn_concurrency) {
+                churn.open(swarm, args.receiver(), params)?;
+            }
+            Ok(ActiveRun::StreamChurn(churn))
+        }
+        BenchMode::ConnectionRate => {
+            let addr = direct_addr
+                .clone()
+                .ok_or("no direct address of the receiver is known")?;
+            info!("Dialing fresh connections to {}", addr);
+            let mut rate = ConnectionRate {
+                addr,
+                connections: args.connections,
+                started: Instant::now(),
+                pending: None,
+                handshakes: Histogram::default(),
+                failed: 0,
+            };
+            rate.next(swarm, args.receiver())?;
+            Ok(ActiveRun::ConnectionRate(rate))
+        }
     }
 }
 
 async fn build_swarm(
-    seed: u8,
+    args: &Args,
     tcp_config: tcp::Config,
-) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
-    let local_key = generate_ed25519(seed);
+    write_stalls: &WriteStalls,
+) -> Result<(Swarm<Behaviour>, Arc<BandwidthSinks>), Box<dyn Error>> {
+    let local_key = match args.common.seed {
+        Some(seed) => generate_ed25519(seed),
+        None => Keypair::generate_ed25519(),
+    };
     let local_peer_id = PeerId::from(local_key.public());
+    info!("Local peer id: {}", local_peer_id);
 
     let (relay_transport, client) = relay::client::new(local_peer_id);
 
-    let transport = {
-        let relay_tcp_quic_transport = relay_transport
-            .or_transport(tcp::async_io::Transport::new(tcp_config))
-            .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&local_key).unwrap())
-            .multiplex(yamux::Config::default())
-            .or_transport(quic::async_std::Transport::new(quic::Config::new(
-                &local_key,
-            )));
-
-        dns::DnsConfig::system(relay_tcp_quic_transport)
-            .await
-            .unwrap()
-            .map(|either_output, _| match either_output {
-                Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
-                Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+    let write_stalls = write_stalls.clone();
+    let transport = build_transport_with(
+        &local_key,
+        relay_transport,
+        TransportOptions {
+            tcp_config,
+            tcp_quickack: args.tcp_quickack,
+            keepalive_interval: args.keepalive_interval,
+            insecure_plaintext: args.insecure_plaintext,
+            dial_timeout: args.dial_timeout,
+        },
+        |transport| {
+            Transport::map(transport, move |(peer_id, muxer), _| {
+                (peer_id, write_stalls.instrument(muxer))
             })
             .boxed()
-    };
+        },
+    )
+    .await?;
+    let (transport, bandwidth) = transport.with_bandwidth_logging();
 
+    let ping_config = match args.keepalive_interval {
+        Some(interval) => ping::Config::new().with_interval(interval),
+        None => ping::Config::new(),
+    };
     let behaviour = Behaviour {
         relay_client: client,
-        ping: ping::Behaviour::new(ping::Config::new()),
+        ping: ping::Behaviour::new(ping_config),
         identify: identify::Behaviour::new(identify::Config::new(
             "/TODO/0.0.1".to_string(),
             local_key.public(),
         )),
         dcutr: dcutr::Behaviour::new(local_peer_id),
         perf: Default::default(),
+        signal: signal::new_behaviour(args.common.signal_protocol()),
+        relay_stats: stats::new_behaviour(ProtocolSupport::Outbound),
     };
 
-    Ok(SwarmBuilder::with_async_std_executor(transport, behaviour, local_peer_id).build())
+    let mut config = swarm::Config::with_async_std_executor()
+        .with_dial_concurrency_factor(args.dial_concurrency_factor);
+    if let Some(interval) = args.keepalive_interval {
+        config = config.with_idle_connection_timeout(interval * KEEPALIVE_IDLE_INTERVALS);
+    }
+    let swarm = Swarm::new(transport, behaviour, local_peer_id, config);
+    Ok((swarm, bandwidth))
 }
 
-async fn learn_external_address(
-    swarm: &mut Swarm<Behaviour>,
-    relay_address: Multiaddr,
-) -> Result<(), Box<dyn Error>> {
-    // Connect to the relay server. Not for the reservation or relayed
-    // connection, but to (a) learn our local public address and (b) enable
-    // a freshly started relay to learn its public address.
-    swarm.dial(relay_address.clone())?;
-    let mut learned_observed_addr = false;
-    let mut told_relay_observed_addr = false;
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
 
-    loop {
-        match swarm.next().await.unwrap() {
-            SwarmEvent::NewListenAddr { .. } => {}
-            SwarmEvent::Dialing { .. } => {}
-            SwarmEvent::ConnectionEstablished { .. } => {}
-            SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent { .. })) => {
-                info!("Notified relay of its public address");
-                told_relay_observed_addr = true;
-            }
-            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
-                info: identify::Info { observed_addr, .. },
-                ..
-            })) => {
-                info!("Relay says our public address is {}", observed_addr);
-                swarm.add_external_address(observed_addr);
-                learned_observed_addr = true;
-            }
-            event => panic!("{event:?}"),
-        }
+    use super::*;
+    use crate::expand_preset;
 
-        if learned_observed_addr && told_relay_observed_addr {
-            return Ok(());
+    #[derive(Parser)]
+    #[command(args_override_self = true)]
+    struct Cli {
+        #[command(flatten)]
+        args: Args,
+    }
+
+    fn parse(args: &[&str]) -> Args {
+        let args = ["send", "--seed", "1", "-r", "/ip4/127.0.0.1/tcp/4001"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string());
+        Cli::try_parse_from(expand_preset(args)).unwrap().args
+    }
+
+    #[test]
+    fn preset_sets_flags() {
+        let args = parse(&["--preset", "smoke"]);
+        assert_eq!(args.preset, Some(Preset::Smoke));
+        assert_eq!(args.payload, 1_000_000);
+        assert_eq!(args.runs, 1);
+        assert_eq!(args.dial_timeout, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn explicit_flags_override_preset() {
+        for args in [
+            parse(&["--preset", "smoke", "--runs", "3"]),
+            parse(&["--runs", "3", "--preset=smoke"]),
+        ] {
+            assert_eq!(args.runs, 3);
+            assert_eq!(args.payload, 1_000_000);
         }
     }
 }
@@ -0,0 +1,185 @@
+//! `/bench/signal/1`, a request-response protocol between sender and receiver
+//! to coordinate experiments independently of DCUtR's own signaling.
+//!
+//! The sender proposes its run plan over the relayed connection as soon as
+//! it is established. The receiver accepts or rejects it, and the sender only
+//! starts runs once the plan was accepted. Both sides send the connection
+//! settings they use so mismatched configurations can be flagged. After a
+//! throughput run, the sender may ask for the receiver's timing of the
+//! transfer to cross-check its own measurement.
+//!
+//! The `/bench` prefix of the protocol name can be overridden, so
+//! independent experiments sharing relays do not answer each other's plans.
+
+use std::time::SystemTime;
+
+use libp2p::request_response::{self, json, ProtocolSupport};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+
+use crate::transfers::Transfer;
+use crate::{stream_receive_window, BenchMode, ConnectionMode, TransportMethod};
+
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/bench/signal/1");
+
+/// Prefix of [`PROTOCOL_NAME`].
+pub const DEFAULT_PROTOCOL_PREFIX: &str = "/bench";
+
+pub type Behaviour = json::Behaviour<Request, Response>;
+
+pub type Event = request_response::Event<Request, Response>;
+
+/// Name of the protocol under `prefix`, as checked by [`parse_protocol_prefix`].
+pub fn protocol_name(prefix: &str) -> StreamProtocol {
+    StreamProtocol::try_from_owned(format!("{prefix}/signal/1"))
+        .expect("protocol prefixes start with a slash")
+}
+
+/// Parse a protocol prefix such as `/mylab/bench`.
+pub fn parse_protocol_prefix(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s.ends_with('/') || s.contains(char::is_whitespace) {
+        return Err(format!(
+            "invalid protocol prefix '{s}': expected a path such as /mylab/bench"
+        ));
+    }
+    Ok(s.to_string())
+}
+
+pub fn new_behaviour(protocol: StreamProtocol) -> Behaviour {
+    json::Behaviour::new(
+        [(protocol, ProtocolSupport::Full)],
+        request_response::Config::default(),
+    )
+}
+
+/// Parameters of the runs a sender is about to perform.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunPlan {
+    pub bench: BenchMode,
+    pub payload: usize,
+    pub runs: usize,
+    pub connection_mode: ConnectionMode,
+    /// Bytes uploaded and downloaded per throughput run.
+    #[serde(default)]
+    pub upload_bytes: usize,
+    #[serde(default)]
+    pub download_bytes: usize,
+}
+
+/// Connection settings a peer uses.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerConfig {
+    pub transport: TransportMethod,
+    pub security: String,
+    pub muxer: String,
+    pub stream_receive_window: usize,
+    pub nodelay: Option<bool>,
+    #[serde(default)]
+    pub quickack: Option<bool>,
+}
+
+impl PeerConfig {
+    pub fn local(transport: TransportMethod, insecure_plaintext: bool, quickack: bool) -> Self {
+        let (security, muxer, nodelay) = match transport {
+            TransportMethod::QuicV1 => ("tls", "quic", None),
+            TransportMethod::Tcp => (Self::tcp_security(insecure_plaintext), "yamux", Some(false)),
+            TransportMethod::TcpNoDelay => {
+                (Self::tcp_security(insecure_plaintext), "yamux", Some(true))
+            }
+        };
+        PeerConfig {
+            transport,
+            security: security.to_string(),
+            muxer: muxer.to_string(),
+            stream_receive_window: stream_receive_window(transport),
+            nodelay,
+            quickack: nodelay.map(|_| quickack),
+        }
+    }
+
+    fn tcp_security(insecure_plaintext: bool) -> &'static str {
+        if insecure_plaintext {
+            "plaintext"
+        } else {
+            "noise"
+        }
+    }
+
+    /// Names of the settings that differ from `other`.
+    pub fn mismatches(&self, other: &PeerConfig) -> Vec<&'static str> {
+        let mut mismatches = Vec::new();
+        if self.transport != other.transport {
+            mismatches.push("transport");
+        }
+        if self.security != other.security {
+            mismatches.push("security");
+        }
+        if self.muxer != other.muxer {
+            mismatches.push("muxer");
+        }
+        if self.stream_receive_window != other.stream_receive_window {
+            mismatches.push("stream receive window");
+        }
+        if self.nodelay != other.nodelay {
+            mismatches.push("nodelay");
+        }
+        if self.quickack != other.quickack {
+            mismatches.push("quickack");
+        }
+        mismatches
+    }
+}
+
+impl std::fmt::Display for PeerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "transport={:?} security={} muxer={} window={} nodelay={} quickack={}",
+            self.transport,
+            self.security,
+            self.muxer,
+            self.stream_receive_window,
+            self.nodelay
+                .map_or("n/a".to_string(), |nodelay| nodelay.to_string()),
+            self.quickack
+                .map_or("n/a".to_string(), |quickack| quickack.to_string()),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Request {
+    Propose {
+        plan: RunPlan,
+        config: PeerConfig,
+    },
+    /// Ask for the receiver's timing of the latest transfer of at least the
+    /// given sizes.
+    Report {
+        upload_bytes: usize,
+        download_bytes: usize,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Response {
+    Accepted {
+        plan: RunPlan,
+        config: PeerConfig,
+        /// Receiver's wall clock when answering, used to estimate clock skew.
+        unix_time_ms: u64,
+    },
+    Rejected {
+        reason: String,
+    },
+    Report {
+        transfer: Option<Transfer>,
+    },
+}
+
+/// Milliseconds since the Unix epoch according to the local clock.
+pub fn unix_time_ms(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
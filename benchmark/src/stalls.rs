@@ -0,0 +1,175 @@
+//! Counts of stream writes the muxer could not accept right away. With yamux,
+//! a write stalls once the stream's send window is exhausted, i.e., until the
+//! remote grants more credit, so frequent and long stalls suggest a run was
+//! window-limited rather than limited by the path. A stall may also be caused
+//! by the connection's outbound frame queue being full.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::io::{IoSlice, IoSliceMut};
+use futures::prelude::*;
+use libp2p::core::muxing::{StreamMuxerBox, SubstreamBox};
+
+use crate::muxing::wrap_substreams;
+
+#[derive(Debug, Default)]
+struct Counters {
+    stalled_streams: AtomicU64,
+    stalls: AtomicU64,
+    stalled_micros: AtomicU64,
+}
+
+/// Write stalls of all streams of the instrumented connections.
+#[derive(Clone, Default)]
+pub struct WriteStalls {
+    counters: Arc<Counters>,
+}
+
+/// Write stalls counted until a point in time, or between two points with
+/// [`StallStats::since`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StallStats {
+    pub stalled_streams: u64,
+    pub stalls: u64,
+    pub stalled: Duration,
+}
+
+impl StallStats {
+    /// Stalls counted after `earlier`.
+    pub fn since(self, earlier: StallStats) -> StallStats {
+        StallStats {
+            stalled_streams: self.stalled_streams - earlier.stalled_streams,
+            stalls: self.stalls - earlier.stalls,
+            stalled: self.stalled.saturating_sub(earlier.stalled),
+        }
+    }
+}
+
+impl fmt::Display for StallStats {
+    /// Format as, e.g., `12 write stalls for 0.0312 s on 1 stream`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} write stalls for {:.4} s on {} stream{}",
+            self.stalls,
+            self.stalled.as_secs_f64(),
+            self.stalled_streams,
+            if self.stalled_streams == 1 { "" } else { "s" }
+        )
+    }
+}
+
+impl WriteStalls {
+    /// Wrap the muxer of a connection so write stalls of its streams are
+    /// counted.
+    pub fn instrument(&self, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        let counters = self.counters.clone();
+        wrap_substreams(muxer, move |inner| {
+            SubstreamBox::new(StallingStream {
+                inner,
+                counters: counters.clone(),
+                stalled: false,
+                stalled_since: None,
+            })
+        })
+    }
+
+    /// Stalls counted so far. Stalls still in progress are not included.
+    pub fn snapshot(&self) -> StallStats {
+        let counters = &self.counters;
+        StallStats {
+            stalled_streams: counters.stalled_streams.load(Ordering::Relaxed),
+            stalls: counters.stalls.load(Ordering::Relaxed),
+            stalled: Duration::from_micros(counters.stalled_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+struct StallingStream {
+    inner: SubstreamBox,
+    counters: Arc<Counters>,
+    /// Whether a write on this stream stalled before.
+    stalled: bool,
+    stalled_since: Option<Instant>,
+}
+
+impl StallingStream {
+    /// Record the outcome of a write.
+    fn record<T>(&mut self, poll: Poll<T>) -> Poll<T> {
+        match &poll {
+            Poll::Pending if self.stalled_since.is_none() => {
+                self.stalled_since = Some(Instant::now());
+                self.counters.stalls.fetch_add(1, Ordering::Relaxed);
+                if !self.stalled {
+                    self.stalled = true;
+                    self.counters
+                        .stalled_streams
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Poll::Pending => {}
+            Poll::Ready(_) => {
+                if let Some(since) = self.stalled_since.take() {
+                    self.counters
+                        .stalled_micros
+                        .fetch_add(since.elapsed().as_micros() as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncRead for StallingStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read_vectored(cx, bufs)
+    }
+}
+
+impl AsyncWrite for StallingStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.record(poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.record(poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
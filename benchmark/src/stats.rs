@@ -0,0 +1,181 @@
+//! Latency histograms in the style of HdrHistogram: values are counted in
+//! log-linear buckets, so percentiles are accurate to within 0.1% of the
+//! value regardless of its magnitude, in constant memory per magnitude.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Values below this many microseconds are counted exactly. Above, each
+/// power of two is split into `SUB_BUCKETS / 2` buckets.
+const SUB_BUCKETS: u64 = 2048;
+
+/// Histogram of latencies with microsecond resolution.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    // Counts by the lowest value of each bucket, in microseconds.
+    counts: BTreeMap<u64, u64>,
+    len: u64,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl Histogram {
+    /// Record one latency.
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        *self.counts.entry(bucket(micros).0).or_default() += 1;
+        self.len += 1;
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = Some(self.max.map_or(latency, |max| max.max(latency)));
+    }
+
+    /// Number of recorded latencies.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    /// The latency at or below which `quantile` (e.g., 0.99) of the recorded
+    /// latencies fall, as the highest value of its bucket but at most the
+    /// maximum recorded latency.
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        let rank = ((quantile * self.len as f64).ceil() as u64).clamp(1, self.len.max(1));
+        let mut seen = 0;
+        for (&lowest, &count) in &self.counts {
+            seen += count;
+            if seen >= rank {
+                let highest = Duration::from_micros(bucket(lowest).1);
+                return Some(highest.min(self.max?));
+            }
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for Histogram {
+    /// Format the latency distribution, e.g., `min 1.02 ms, p50 1.31 ms,
+    /// p90 1.87 ms, p99 2.40 ms, p999 3.01 ms, max 3.05 ms`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (Some(min), Some(max)) = (self.min, self.max) else {
+            return write!(f, "no latencies recorded");
+        };
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        write!(f, "min {:.2} ms", ms(min))?;
+        for (name, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p999", 0.999)] {
+            let latency = self.percentile(quantile).expect("latencies were recorded");
+            write!(f, ", {} {:.2} ms", name, ms(latency))?;
+        }
+        write!(f, ", max {:.2} ms", ms(max))
+    }
+}
+
+/// Lowest and highest value of the bucket counting `micros`.
+fn bucket(micros: u64) -> (u64, u64) {
+    if micros < SUB_BUCKETS {
+        return (micros, micros);
+    }
+    // Keep the top bits of the value so each bucket spans a fixed fraction
+    // of its magnitude.
+    let shift = (u64::BITS - micros.leading_zeros()) - SUB_BUCKETS.trailing_zeros();
+    let lowest = micros >> shift << shift;
+    (lowest, lowest + ((1 << shift) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Relative error the module documentation promises.
+    const MAX_RELATIVE_ERROR: f64 = 0.001;
+
+    /// Check p50, p99, and p999 of `micros` against the exact percentiles.
+    fn assert_percentiles(mut micros: Vec<u64>) {
+        let mut histogram = Histogram::default();
+        for &value in &micros {
+            histogram.record(Duration::from_micros(value));
+        }
+        micros.sort_unstable();
+        for quantile in [0.5, 0.99, 0.999] {
+            let rank = (quantile * micros.len() as f64).ceil() as usize;
+            let exact = micros[rank - 1] as f64;
+            let estimate = histogram.percentile(quantile).unwrap().as_micros() as f64;
+            let error = (estimate - exact).abs() / exact;
+            assert!(
+                error <= MAX_RELATIVE_ERROR,
+                "p{quantile}: estimated {estimate} µs, exact {exact} µs"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_histogram() {
+        let histogram = Histogram::default();
+        assert!(histogram.is_empty());
+        assert_eq!(histogram.len(), 0);
+        assert_eq!(histogram.min(), None);
+        assert_eq!(histogram.max(), None);
+        assert_eq!(histogram.percentile(0.5), None);
+        assert_eq!(histogram.to_string(), "no latencies recorded");
+    }
+
+    #[test]
+    fn single_sample() {
+        for latency in [Duration::from_micros(7), Duration::from_millis(1234)] {
+            let mut histogram = Histogram::default();
+            histogram.record(latency);
+            assert_eq!(histogram.len(), 1);
+            assert_eq!(histogram.min(), Some(latency));
+            assert_eq!(histogram.max(), Some(latency));
+            for quantile in [0.0, 0.5, 0.99, 0.999, 1.0] {
+                assert_eq!(histogram.percentile(quantile), Some(latency));
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_distribution() {
+        assert_percentiles((1..=100_000).collect());
+    }
+
+    #[test]
+    fn exponential_distribution() {
+        // Deterministic samples with a mean of 20 ms, from a linear
+        // congruential generator.
+        let mut state: u64 = 1;
+        let micros = (0..100_000)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let uniform = (state >> 11) as f64 / (1u64 << 53) as f64;
+                (-(1.0 - uniform).ln() * 20_000.0) as u64 + 1
+            })
+            .collect();
+        assert_percentiles(micros);
+    }
+
+    #[test]
+    fn values_below_sub_buckets_are_exact() {
+        let mut histogram = Histogram::default();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(500)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_micros(990)));
+        assert_eq!(
+            histogram.percentile(0.999),
+            Some(Duration::from_micros(999))
+        );
+    }
+}
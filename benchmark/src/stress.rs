@@ -0,0 +1,113 @@
+//! Load patterns that put significant load on a relay, such as holding many
+//! reservations from one host. They are gated behind `--stress` so they are
+//! never run against a shared relay by accident.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::StreamExt;
+use libp2p::{
+    core::multiaddr::Multiaddr,
+    identity::Keypair,
+    ping, relay,
+    swarm::{self, NetworkBehaviour, Swarm, SwarmEvent},
+    PeerId,
+};
+use log::{debug, info};
+
+use crate::{build_transport, is_rate_limited, TransportOptions};
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    relay_client: relay::client::Behaviour,
+    ping: ping::Behaviour,
+}
+
+/// Reservations held on one relay by additional identities.
+#[derive(Clone)]
+pub struct Reservations {
+    total: usize,
+    held: Arc<AtomicUsize>,
+}
+
+impl Reservations {
+    /// Track `total` additional identities.
+    pub fn new(total: usize) -> Self {
+        Reservations {
+            total,
+            held: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Hold a reservation on `relay_listen_addr`, a `/p2p-circuit` address,
+    /// with a fresh random identity, numbered `index` in logs, until the
+    /// process exits. Fails once the relay refused the reservation.
+    pub async fn hold(
+        &self,
+        relay_listen_addr: Multiaddr,
+        options: TransportOptions,
+        index: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let local_key = Keypair::generate_ed25519();
+        let local_peer_id = PeerId::from(local_key.public());
+        let (relay_transport, client) = relay::client::new(local_peer_id);
+        let transport = build_transport(&local_key, relay_transport, options).await?;
+        let behaviour = Behaviour {
+            relay_client: client,
+            ping: ping::Behaviour::new(ping::Config::new()),
+        };
+        let mut swarm = Swarm::new(
+            transport,
+            behaviour,
+            local_peer_id,
+            swarm::Config::with_async_std_executor(),
+        );
+
+        swarm.listen_on(relay_listen_addr)?;
+        let requested_at = Instant::now();
+        loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                    relay::client::Event::ReservationReqAccepted { renewal, .. },
+                )) => {
+                    if renewal {
+                        debug!("Stress identity {} renewed its reservation", index);
+                        continue;
+                    }
+                    let held = self.held.fetch_add(1, Ordering::Relaxed) + 1;
+                    info!(
+                        "Stress identity {}/{} ({}) holds a reservation, accepted in {:.4} s \
+                         ({} of {} held)",
+                        index,
+                        self.total,
+                        local_peer_id,
+                        requested_at.elapsed().as_secs_f64(),
+                        held,
+                        self.total
+                    );
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                    relay::client::Event::ReservationReqFailed { renewal, error, .. },
+                )) => {
+                    if renewal {
+                        self.held.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    let reason = if is_rate_limited(&error) {
+                        "rate limited by relay".to_string()
+                    } else {
+                        format!("{error:?}")
+                    };
+                    return Err(format!(
+                        "reservation {} refused: {}",
+                        if renewal { "renewal" } else { "request" },
+                        reason
+                    )
+                    .into());
+                }
+                event => debug!("Stress identity {}: {:?}", index, event),
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Slow reads on the receiver, to check that backpressure propagates through
+//! the muxer to the sender. Reads of all streams of the instrumented
+//! connections share one token bucket, so the receiver as a whole reads at
+//! most at the given rate. Data that was not read stays buffered in the
+//! muxer, which stops granting the sender credit once its receive window is
+//! full. The resident set size is sampled whenever a read waits, so
+//! unbounded buffering shows up as a growing peak.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::io::IoSlice;
+use futures::prelude::*;
+use futures::ready;
+use futures_timer::Delay;
+use libp2p::core::muxing::{StreamMuxerBox, SubstreamBox};
+
+use crate::muxing::wrap_substreams;
+use crate::resident_set_size;
+
+/// Longest burst the bucket allows, as time at the configured rate.
+const BURST: Duration = Duration::from_millis(100);
+
+struct TokenBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Bytes up to `wanted` that may be read now, or how long to wait until
+    /// as many as `wanted`, or a full burst, may. Waiting for more than a
+    /// byte keeps reads from spinning on a trickle of tokens.
+    fn available(&mut self, wanted: usize) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity);
+        self.refilled_at = now;
+        let wanted = (wanted as f64).min(self.capacity.floor());
+        if self.tokens >= wanted {
+            Ok(wanted as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (wanted - self.tokens) / self.bytes_per_sec,
+            ))
+        }
+    }
+}
+
+/// Read rate limit of all streams of the instrumented connections.
+#[derive(Clone)]
+pub struct ReadThrottle {
+    bucket: Arc<Mutex<TokenBucket>>,
+    peak_rss: Arc<AtomicU64>,
+}
+
+impl ReadThrottle {
+    /// Read at most `bytes_per_sec` bytes per second.
+    pub fn new(bytes_per_sec: usize) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        let capacity = (bytes_per_sec * BURST.as_secs_f64()).max(1.0);
+        ReadThrottle {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                bytes_per_sec,
+                capacity,
+                tokens: capacity,
+                refilled_at: Instant::now(),
+            })),
+            peak_rss: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Wrap the muxer of a connection so reads of its streams are throttled.
+    pub fn instrument(&self, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        let throttle = self.clone();
+        wrap_substreams(muxer, move |inner| {
+            SubstreamBox::new(ThrottledStream {
+                inner,
+                throttle: throttle.clone(),
+                delay: None,
+            })
+        })
+    }
+
+    /// Highest resident set size sampled while a read waited, if any did.
+    pub fn peak_rss(&self) -> Option<u64> {
+        Some(self.peak_rss.load(Ordering::Relaxed)).filter(|rss| *rss > 0)
+    }
+}
+
+struct ThrottledStream {
+    inner: SubstreamBox,
+    throttle: ReadThrottle,
+    delay: Option<Delay>,
+}
+
+impl AsyncRead for ThrottledStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                ready!(delay.poll_unpin(cx));
+                this.delay = None;
+            }
+            let available = this
+                .throttle
+                .bucket
+                .lock()
+                .expect("not poisoned")
+                .available(buf.len());
+            match available {
+                Ok(len) => {
+                    let poll = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]);
+                    if let Poll::Ready(Ok(n)) = &poll {
+                        this.throttle.bucket.lock().expect("not poisoned").tokens -= *n as f64;
+                    }
+                    return poll;
+                }
+                Err(wait) => {
+                    if let Ok(rss) = resident_set_size() {
+                        this.throttle.peak_rss.fetch_max(rss, Ordering::Relaxed);
+                    }
+                    this.delay = Some(Delay::new(wait));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for ThrottledStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
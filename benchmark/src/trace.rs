@@ -0,0 +1,134 @@
+//! Timelines of benchmark phases and runs in the Chrome trace event format,
+//! which about://tracing and Perfetto display far better than log
+//! timestamps, e.g., for the overlapping streams of stream-churn runs.
+
+use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct Event {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    /// Microseconds since the trace started.
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    pid: u32,
+    tid: u64,
+    args: serde_json::Value,
+}
+
+/// Events recorded so far, relative to when the trace was created.
+pub struct Trace {
+    origin: Instant,
+    events: Vec<Event>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace {
+            origin: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Record a span of `duration` from `start` on track `tid`. Spans on the
+    /// same track must nest.
+    pub fn span(
+        &mut self,
+        name: impl Into<String>,
+        cat: &'static str,
+        tid: u64,
+        start: Instant,
+        duration: Duration,
+        args: serde_json::Value,
+    ) {
+        self.events.push(Event {
+            name: name.into(),
+            cat,
+            ph: "X",
+            ts: self.micros(start),
+            dur: Some(duration.as_secs_f64() * 1e6),
+            id: None,
+            pid: std::process::id(),
+            tid,
+            args,
+        });
+    }
+
+    /// Record a span from `start` until now that may overlap other spans of
+    /// `cat`, e.g., a stream among concurrently open streams.
+    pub fn async_span(
+        &mut self,
+        name: impl Into<String>,
+        cat: &'static str,
+        id: u64,
+        start: Instant,
+        args: serde_json::Value,
+    ) {
+        let name = name.into();
+        let end = self.micros(Instant::now());
+        for (ph, ts, args) in [
+            ("b", self.micros(start), args),
+            ("e", end, serde_json::json!({})),
+        ] {
+            self.events.push(Event {
+                name: name.clone(),
+                cat,
+                ph,
+                ts,
+                dur: None,
+                id: Some(id),
+                pid: std::process::id(),
+                tid: 0,
+                args,
+            });
+        }
+    }
+
+    /// Record the values of counters, e.g., throughput, at `at`. Trace
+    /// viewers plot each counter of `name` as a graph over time.
+    pub fn counter(&mut self, name: impl Into<String>, at: Instant, args: serde_json::Value) {
+        self.events.push(Event {
+            name: name.into(),
+            cat: "counter",
+            ph: "C",
+            ts: self.micros(at),
+            dur: None,
+            id: None,
+            pid: std::process::id(),
+            tid: 0,
+            args,
+        });
+    }
+
+    /// Write the trace to `path` as a JSON object, replacing the file
+    /// atomically.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let trace = serde_json::json!({
+            "traceEvents": self.events,
+            "displayTimeUnit": "ms",
+        });
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string(&trace)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn micros(&self, at: Instant) -> f64 {
+        at.saturating_duration_since(self.origin).as_secs_f64() * 1e6
+    }
+}
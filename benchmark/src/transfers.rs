@@ -0,0 +1,218 @@
+//! Timing of perf transfers as seen by the receiver, so the throughput the
+//! sender measured can be cross-checked. The perf server does not report
+//! runs, so streams are timed at the muxer instead: the upload lasts from the
+//! first to the last byte read, and the download from the end of the upload
+//! until the stream is closed, since the server only starts responding once
+//! the upload is complete.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::io::{IoSlice, IoSliceMut};
+use futures::prelude::*;
+use futures::ready;
+use libp2p::core::muxing::{StreamMuxerBox, SubstreamBox};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::muxing::wrap_substreams;
+
+/// Completed transfers kept per peer.
+const MAX_TRANSFERS: usize = 16;
+
+/// A stream that was closed after reading and writing, as timed by the
+/// receiver. Byte counts include protocol negotiation and headers.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub read_bytes: usize,
+    pub upload: Duration,
+    pub written_bytes: usize,
+    pub download: Duration,
+    /// The receiver's peak resident set size while reads were throttled by
+    /// --read-rate, in bytes.
+    #[serde(default)]
+    pub peak_rss: Option<u64>,
+}
+
+/// Receives each completed transfer with the peer it was with.
+type Subscriber = mpsc::UnboundedSender<(PeerId, Transfer)>;
+
+/// Transfers of all streams of the instrumented connections, by peer.
+#[derive(Clone, Default)]
+pub struct TransferTimings {
+    completed: Arc<Mutex<HashMap<PeerId, VecDeque<Transfer>>>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl TransferTimings {
+    /// Wrap the muxer of a connection to `peer_id` so its streams are timed.
+    pub fn instrument(&self, peer_id: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        let timings = self.clone();
+        wrap_substreams(muxer, move |inner| {
+            SubstreamBox::new(TimedStream {
+                inner,
+                peer_id,
+                timings: timings.clone(),
+                read_bytes: 0,
+                first_read: None,
+                last_read: None,
+                eof_at: None,
+                written_bytes: 0,
+                recorded: false,
+            })
+        })
+    }
+
+    /// Receive each transfer as it completes, in addition to keeping it for
+    /// [`TransferTimings::take`].
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<(PeerId, Transfer)> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.lock().expect("not poisoned").push(sender);
+        receiver
+    }
+
+    /// Remove and return the latest transfer with `peer` that read at least
+    /// `upload_bytes` and wrote at least `download_bytes`.
+    pub fn take(
+        &self,
+        peer: &PeerId,
+        upload_bytes: usize,
+        download_bytes: usize,
+    ) -> Option<Transfer> {
+        let mut completed = self.completed.lock().expect("not poisoned");
+        let transfers = completed.get_mut(peer)?;
+        let position = transfers.iter().rposition(|transfer| {
+            transfer.read_bytes >= upload_bytes && transfer.written_bytes >= download_bytes
+        })?;
+        transfers.remove(position)
+    }
+}
+
+struct TimedStream {
+    inner: SubstreamBox,
+    peer_id: PeerId,
+    timings: TransferTimings,
+    read_bytes: usize,
+    first_read: Option<Instant>,
+    last_read: Option<Instant>,
+    eof_at: Option<Instant>,
+    written_bytes: usize,
+    recorded: bool,
+}
+
+impl TimedStream {
+    /// Record the outcome of a read.
+    fn record_read(&mut self, poll: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(n)) = &poll {
+            let now = Instant::now();
+            if *n == 0 {
+                self.eof_at.get_or_insert(now);
+            } else {
+                self.read_bytes += n;
+                self.first_read.get_or_insert(now);
+                self.last_read = Some(now);
+            }
+        }
+        poll
+    }
+
+    /// Record the outcome of a write.
+    fn record_write(&mut self, poll: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(n)) = &poll {
+            self.written_bytes += n;
+        }
+        poll
+    }
+
+    /// Record the transfer once the stream is closed after reading and
+    /// writing.
+    fn record_close(&mut self) {
+        let (Some(first_read), Some(last_read)) = (self.first_read, self.last_read) else {
+            return;
+        };
+        if self.recorded || self.written_bytes == 0 {
+            return;
+        }
+        self.recorded = true;
+        let transfer = Transfer {
+            read_bytes: self.read_bytes,
+            upload: last_read - first_read,
+            written_bytes: self.written_bytes,
+            download: self.eof_at.unwrap_or(last_read).elapsed(),
+            peak_rss: None,
+        };
+        let mut completed = self.timings.completed.lock().expect("not poisoned");
+        let transfers = completed.entry(self.peer_id).or_default();
+        if transfers.len() == MAX_TRANSFERS {
+            transfers.pop_front();
+        }
+        transfers.push_back(transfer);
+        self.timings
+            .subscribers
+            .lock()
+            .expect("not poisoned")
+            .retain(|subscriber| subscriber.unbounded_send((self.peer_id, transfer)).is_ok());
+    }
+}
+
+impl AsyncRead for TimedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.record_read(poll)
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read_vectored(cx, bufs);
+        this.record_read(poll)
+    }
+}
+
+impl AsyncWrite for TimedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.record_write(poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.record_write(poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = ready!(Pin::new(&mut this.inner).poll_close(cx));
+        // The remote may go away before acknowledging the close once it read
+        // everything, e.g., a sender exiting after its last run.
+        this.record_close();
+        Poll::Ready(result)
+    }
+}
@@ -0,0 +1,94 @@
+//! Structured warnings about conditions that make results less reliable.
+//! Warnings are logged when first detected and included in run records so
+//! they are not lost when only the results are kept.
+
+use std::time::Duration;
+
+use log::warn;
+
+/// Skew between the sender's and receiver's clocks above which timestamps
+/// of the two sides should not be compared.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(1);
+
+/// Runs shorter than this are dominated by connection and stream setup.
+pub const MIN_RUN_DURATION: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub enum Warning {
+    /// The relay only observed loopback or private addresses of this node.
+    NoPublicAddress,
+    /// A relayed connection to the receiver was open during a run, so some
+    /// perf requests may have been sent over the relay.
+    RelayedConnection,
+    /// Estimated offset of the receiver's clock in seconds.
+    ClockSkew(f64),
+    /// A direction of a run completed too quickly to be measured reliably.
+    PayloadTooSmall(Duration),
+}
+
+impl Warning {
+    /// Stable identifier used in run records.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::NoPublicAddress => "no-public-address",
+            Warning::RelayedConnection => "relayed-connection",
+            Warning::ClockSkew(_) => "clock-skew",
+            Warning::PayloadTooSmall(_) => "payload-too-small",
+        }
+    }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::NoPublicAddress => {
+                write!(f, "no public external address learned from the relay")
+            }
+            Warning::RelayedConnection => {
+                write!(
+                    f,
+                    "relayed connection to the receiver was open during a run"
+                )
+            }
+            Warning::ClockSkew(skew) => write!(
+                f,
+                "receiver clock is {:+.3} s off, more than {} s",
+                skew,
+                MAX_CLOCK_SKEW.as_secs_f64()
+            ),
+            Warning::PayloadTooSmall(duration) => write!(
+                f,
+                "payload too small for a reliable measurement: transfer took {:.4} s, \
+                 less than {} s",
+                duration.as_secs_f64(),
+                MIN_RUN_DURATION.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Warnings detected so far, at most one per kind.
+#[derive(Clone, Debug, Default)]
+pub struct Warnings(Vec<Warning>);
+
+impl Warnings {
+    /// Log `warning` and add it unless a warning of the same kind exists.
+    pub fn push(&mut self, warning: Warning) {
+        if self.0.iter().any(|w| w.code() == warning.code()) {
+            return;
+        }
+        warn!("{}", warning);
+        self.0.push(warning);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Display for Warnings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let codes: Vec<_> = self.0.iter().map(Warning::code).collect();
+        write!(f, "{}", codes.join(", "))
+    }
+}
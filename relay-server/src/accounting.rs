@@ -0,0 +1,196 @@
+//! Per-client byte counters, so relayed-path benchmarks can be cross-checked
+//! against what the relay actually forwarded. Counts include all streams of a
+//! client's connections, i.e., circuits as well as identify and ping traffic.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::io::{IoSlice, IoSliceMut};
+use futures::prelude::*;
+use futures::ready;
+use libp2p::core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox};
+use libp2p::identity::PeerId;
+
+/// Bytes received from and sent to one client over all its connections.
+#[derive(Debug, Default)]
+pub struct Counters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+impl Counters {
+    pub fn inbound(&self) -> u64 {
+        self.inbound.load(Ordering::Relaxed)
+    }
+
+    pub fn outbound(&self) -> u64 {
+        self.outbound.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters of every client that connected since the relay started.
+#[derive(Clone, Default)]
+pub struct Accounting {
+    clients: Arc<Mutex<HashMap<PeerId, Arc<Counters>>>>,
+}
+
+impl Accounting {
+    /// Counters of `peer_id`, created on its first connection.
+    pub fn counters(&self, peer_id: PeerId) -> Arc<Counters> {
+        self.clients
+            .lock()
+            .expect("not poisoned")
+            .entry(peer_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Wrap the muxer of a connection to `peer_id` so its streams are counted.
+    pub fn instrument(&self, peer_id: PeerId, muxer: StreamMuxerBox) -> StreamMuxerBox {
+        StreamMuxerBox::new(CountingMuxer {
+            inner: muxer,
+            counters: self.counters(peer_id),
+        })
+    }
+
+    /// Write the counters of all clients to `path` as JSON, replacing the
+    /// file atomically.
+    pub fn dump(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let clients: BTreeMap<String, serde_json::Value> = self
+            .clients
+            .lock()
+            .expect("not poisoned")
+            .iter()
+            .map(|(peer_id, counters)| {
+                (
+                    peer_id.to_string(),
+                    serde_json::json!({
+                        "inbound": counters.inbound(),
+                        "outbound": counters.outbound(),
+                    }),
+                )
+            })
+            .collect();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(&clients)? + "\n")?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+struct CountingMuxer {
+    inner: StreamMuxerBox,
+    counters: Arc<Counters>,
+}
+
+impl StreamMuxer for CountingMuxer {
+    type Substream = SubstreamBox;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let inner = ready!(Pin::new(&mut this.inner).poll_inbound(cx)?);
+        Poll::Ready(Ok(SubstreamBox::new(CountingStream {
+            inner,
+            counters: this.counters.clone(),
+        })))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        let inner = ready!(Pin::new(&mut this.inner).poll_outbound(cx)?);
+        Poll::Ready(Ok(SubstreamBox::new(CountingStream {
+            inner,
+            counters: this.counters.clone(),
+        })))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll(cx)
+    }
+}
+
+struct CountingStream {
+    inner: SubstreamBox,
+    counters: Arc<Counters>,
+}
+
+impl AsyncRead for CountingStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.counters.inbound.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_read_vectored(cx, bufs))?;
+        this.counters.inbound.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.counters
+            .outbound
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write_vectored(cx, bufs))?;
+        this.counters
+            .outbound
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// Copyright 2021 Protocol Labs.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a
+// copy of this software and associated documentation files (the "Software"),
+// to deal in the Software without restriction, including without limitation
+// the rights to use, copy, modify, merge, publish, distribute, sublicense,
+// and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+// OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+// FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+#![doc = include_str!("../README.md")]
+// The README's lists are formatted for GitHub rather than rustdoc.
+#![allow(clippy::doc_lazy_continuation)]
+
+mod accounting;
+pub mod stats;
+
+use accounting::Accounting;
+use clap::Parser;
+use futures::stream::StreamExt;
+use futures::{executor::block_on, future::Either, FutureExt};
+use libp2p::{
+    core::multiaddr::Protocol,
+    core::muxing::StreamMuxerBox,
+    core::upgrade,
+    core::{Multiaddr, Transport},
+    identify, identity,
+    identity::PeerId,
+    noise, ping, plaintext, quic, relay,
+    request_response::{self, ProtocolSupport},
+    swarm::{NetworkBehaviour, SwarmEvent},
+    tcp,
+};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Run the relay server until it fails.
+pub fn run(opt: Opt) -> Result<(), Box<dyn Error>> {
+    info!("opt: {opt:?}");
+
+    // Create a static known PeerId based on given secret
+    let local_key: identity::Keypair = generate_ed25519(opt.secret_key_seed);
+    let local_peer_id = PeerId::from(local_key.public());
+
+    let tcp_transport = tcp::async_io::Transport::default();
+
+    let tcp_transport = if opt.insecure_plaintext {
+        warn!("Using INSECURE plaintext security for TCP connections");
+        tcp_transport
+            .upgrade(upgrade::Version::V1Lazy)
+            .authenticate(plaintext::Config::new(&local_key))
+            .multiplex(libp2p::yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed()
+    } else {
+        tcp_transport
+            .upgrade(upgrade::Version::V1Lazy)
+            .authenticate(
+                noise::Config::new(&local_key)
+                    .expect("Signing libp2p-noise static DH keypair failed."),
+            )
+            .multiplex(libp2p::yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed()
+    };
+
+    let quic_transport = quic::async_std::Transport::new(quic::Config::new(&local_key));
+
+    let accounting = Accounting::default();
+    let transport = quic_transport
+        .or_transport(tcp_transport)
+        .map(|either_output, _| match either_output {
+            Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+            Either::Right((peer_id, muxer)) => (peer_id, muxer),
+        })
+        .map({
+            let accounting = accounting.clone();
+            move |(peer_id, muxer), _| (peer_id, accounting.instrument(peer_id, muxer))
+        })
+        .boxed();
+
+    let behaviour = Behaviour {
+        relay: relay::Behaviour::new(local_peer_id, Default::default()),
+        ping: ping::Behaviour::new(ping::Config::new()),
+        identify: identify::Behaviour::new(identify::Config::new(
+            "/TODO/0.0.1".to_string(),
+            local_key.public(),
+        )),
+        stats: stats::new_behaviour(ProtocolSupport::Inbound),
+    };
+
+    // swarm::Config has no constructor without an executor yet.
+    #[allow(deprecated)]
+    let mut swarm =
+        libp2p::swarm::SwarmBuilder::without_executor(transport, behaviour, local_peer_id).build();
+
+    // Listen on all interfaces, or only on loopback when plaintext is used
+    let listen_ip = match (opt.use_ipv6, opt.insecure_plaintext) {
+        (Some(true), false) => Protocol::from(Ipv6Addr::UNSPECIFIED),
+        (Some(true), true) => Protocol::from(Ipv6Addr::LOCALHOST),
+        (_, false) => Protocol::from(Ipv4Addr::UNSPECIFIED),
+        (_, true) => Protocol::from(Ipv4Addr::LOCALHOST),
+    };
+    let listen_addr_tcp = Multiaddr::empty()
+        .with(listen_ip.clone())
+        .with(Protocol::Tcp(opt.port));
+    swarm.listen_on(listen_addr_tcp)?;
+
+    let listen_addr_quic = Multiaddr::empty()
+        .with(listen_ip)
+        .with(Protocol::Udp(opt.port))
+        .with(Protocol::QuicV1);
+    swarm.listen_on(listen_addr_quic)?;
+
+    let dump_delay = || match &opt.bandwidth_dump {
+        Some(_) => futures_timer::Delay::new(opt.bandwidth_dump_interval).boxed(),
+        None => futures::future::pending().boxed(),
+    };
+    let mut dump = dump_delay().fuse();
+
+    // Latest ping RTT to each connected client, reported via the stats
+    // protocol.
+    let mut rtts: HashMap<PeerId, Duration> = HashMap::new();
+
+    block_on(async {
+        loop {
+            let event = futures::select! {
+                event = swarm.next() => event.expect("Infinite Stream."),
+                _ = dump => {
+                    let path = opt.bandwidth_dump.as_ref().expect("dumps require a path");
+                    if let Err(e) = accounting.dump(path) {
+                        warn!("Failed to write bandwidth dump to {}: {e}", path.display());
+                    }
+                    dump = dump_delay().fuse();
+                    continue;
+                }
+            };
+            match event {
+                SwarmEvent::Behaviour(BehaviourEvent::Stats(
+                    request_response::Event::Message {
+                        message:
+                            request_response::Message::Request {
+                                request, channel, ..
+                            },
+                        ..
+                    },
+                )) => {
+                    let rtts = request
+                        .peers
+                        .iter()
+                        .map(|peer| {
+                            peer.parse::<PeerId>()
+                                .ok()
+                                .and_then(|peer| rtts.get(&peer).copied())
+                        })
+                        .collect();
+                    let _ = swarm
+                        .behaviour_mut()
+                        .stats
+                        .send_response(channel, stats::Response { rtts });
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Stats(_)) => {}
+                SwarmEvent::Behaviour(event) => {
+                    if let BehaviourEvent::Ping(ping::Event {
+                        peer,
+                        result: Ok(rtt),
+                        ..
+                    }) = &event
+                    {
+                        rtts.insert(*peer, *rtt);
+                    }
+
+                    if let BehaviourEvent::Relay(relay::Event::CircuitClosed {
+                        src_peer_id,
+                        dst_peer_id,
+                        ..
+                    }) = &event
+                    {
+                        for peer_id in [src_peer_id, dst_peer_id] {
+                            let counters = accounting.counters(*peer_id);
+                            info!(
+                                "Client {peer_id}: {} bytes received, {} bytes sent",
+                                counters.inbound(),
+                                counters.outbound()
+                            );
+                        }
+                    }
+
+                    if let BehaviourEvent::Identify(identify::Event::Received {
+                        info: identify::Info { observed_addr, .. },
+                        ..
+                    }) = &event
+                    {
+                        swarm.add_external_address(observed_addr.clone());
+                    }
+
+                    info!("{event:?}")
+                }
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    info!("Listening on {address:?}");
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } => {
+                    rtts.remove(&peer_id);
+                }
+                _ => {}
+            }
+        }
+    })
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    relay: relay::Behaviour,
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    stats: stats::Behaviour,
+}
+
+fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = secret_key_seed;
+
+    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+}
+
+#[derive(Debug, Parser)]
+#[clap(name = "libp2p relay")]
+pub struct Opt {
+    /// Determine if the relay listen on ipv6 or ipv4 loopback address. the default is ipv4
+    #[clap(long)]
+    use_ipv6: Option<bool>,
+
+    /// Fixed value to generate deterministic peer id
+    #[clap(long)]
+    secret_key_seed: u8,
+
+    /// The port used to listen on all interfaces
+    #[clap(long)]
+    port: u16,
+
+    /// Use plaintext instead of noise for TCP connections and only listen on
+    /// loopback. INSECURE: for isolating the cost of the security layer
+    #[clap(long)]
+    insecure_plaintext: bool,
+
+    /// Periodically write the bytes received from and sent to each client
+    /// to this JSON file
+    #[clap(long)]
+    bandwidth_dump: Option<PathBuf>,
+
+    /// Interval between writes of the bandwidth dump
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "10s")]
+    bandwidth_dump_interval: Duration,
+}
@@ -0,0 +1,37 @@
+//! `/relay/stats/1`, a request-response protocol through which clients ask
+//! the relay for its latest ping RTT to connected peers, e.g., to split the
+//! RTT of a relayed path into its sender-relay and relay-receiver legs.
+
+use std::time::Duration;
+
+use libp2p::request_response::{self, json, ProtocolSupport};
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+
+pub const PROTOCOL_NAME: StreamProtocol = StreamProtocol::new("/relay/stats/1");
+
+pub type Behaviour = json::Behaviour<Request, Response>;
+
+pub type Event = request_response::Event<Request, Response>;
+
+/// The relay serves requests with `ProtocolSupport::Inbound`, clients send
+/// them with `ProtocolSupport::Outbound`.
+pub fn new_behaviour(support: ProtocolSupport) -> Behaviour {
+    json::Behaviour::new(
+        [(PROTOCOL_NAME, support)],
+        request_response::Config::default(),
+    )
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Request {
+    /// Peer ids of the peers to report.
+    pub peers: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Response {
+    /// Latest RTT to each requested peer, `None` if the relay is not connected
+    /// to it or has not pinged it yet.
+    pub rtts: Vec<Option<Duration>>,
+}